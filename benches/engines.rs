@@ -42,7 +42,7 @@ fn get_bench(c: &mut Criterion) {
     let mut group = c.benchmark_group("get_bench");
     //group.measurement_time(std::time::Duration::from_secs(10));
     group.sample_size(10);
-    for i in &vec![4, 6, 8, 10] {
+    for i in &[4, 6, 8, 10] {
         group.bench_with_input(format!("kvs_{}", i), i, |b, i| {
             let temp_dir = TempDir::new().unwrap();
             let store = KvStore::open(temp_dir.path()).unwrap();
@@ -59,7 +59,7 @@ fn get_bench(c: &mut Criterion) {
             })
         });
     }
-    for i in &vec![4, 6, 8, 10] {
+    for i in &[4, 6, 8, 10] {
         group.bench_with_input(format!("sled_{}", i), i, |b, i| {
             let temp_dir = TempDir::new().unwrap();
             let db = SledKvsEngine::open(temp_dir.path()).unwrap();
@@ -1,6 +1,7 @@
-use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use crossbeam_utils::sync::WaitGroup;
 use kvs::thread_pool::{RayonThreadPool, SharedQueueThreadPool, ThreadPool};
-use kvs::{KvStore, KvsEngine, SledKvsEngine};
+use kvs::{KvStore, KvsEngine, SledKvsEngine, SyncPolicy};
 use rand::prelude::*;
 use tempfile::TempDir;
 
@@ -141,5 +142,46 @@ fn pool_get_bench(c: &mut Criterion) {
     group.finish();
 }
 
-criterion_group!(benches, pool_get_bench, pool_set_bench);
+// Durable sets from a growing number of writers. With group commit, concurrent
+// writers share one write+fsync, so throughput grows with the thread count.
+fn group_commit_bench(c: &mut Criterion) {
+    const SETS: u64 = 256;
+    let mut group = c.benchmark_group("group_commit_bench");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(SETS));
+    for threads in [1, 4, 16] {
+        group.bench_with_input(
+            BenchmarkId::new("SharedQueueThreadPool + kvs(EveryWrite)", threads),
+            &threads,
+            |b, &threads| {
+                b.iter_batched(
+                    || {
+                        let temp_dir = TempDir::new().unwrap();
+                        let store =
+                            KvStore::open_with_sync(temp_dir.path(), SyncPolicy::EveryWrite)
+                                .unwrap();
+                        let pool = SharedQueueThreadPool::new(threads).unwrap();
+                        (temp_dir, store, pool)
+                    },
+                    |(_temp_dir, store, pool)| {
+                        let wg = WaitGroup::new();
+                        for i in 0..SETS {
+                            let store = store.clone();
+                            let wg = wg.clone();
+                            pool.spawn(move || {
+                                store.set(format!("key{}", i), "value".to_string()).unwrap();
+                                drop(wg);
+                            });
+                        }
+                        wg.wait();
+                    },
+                    BatchSize::SmallInput,
+                )
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, pool_get_bench, pool_set_bench, group_commit_bench);
 criterion_main!(benches);
@@ -1,7 +1,8 @@
 use clap::{Args, Parser, Subcommand};
-use kvs::{Message, Result};
-use std::io::{Read, Write};
-use std::net::TcpStream;
+use kvs::client::KvsClient;
+use kvs::{ErrorKind, Result};
+use std::ops::Bound;
+use std::time::Duration;
 
 #[derive(Parser)]
 #[command(
@@ -33,6 +34,10 @@ enum Commands {
     Set(SetCommand),
     /// Remove a given key
     Rm(RemoveCommand),
+    /// Set the value of a key only if it currently has an expected value
+    Cas(CasCommand),
+    /// List the key/value pairs in a range of keys
+    Scan(ScanCommand),
 }
 
 #[derive(Args)]
@@ -54,6 +59,9 @@ struct SetCommand {
     key: String,
     /// The string value of the key.
     val: String,
+    /// Expire the key after this many seconds.
+    #[arg(long, value_name = "SECONDS")]
+    ttl: Option<u64>,
 
     #[arg(
         long = "addr",
@@ -76,33 +84,90 @@ struct RemoveCommand {
     addr: String,
 }
 
+#[derive(Args)]
+struct CasCommand {
+    /// A string key.
+    key: String,
+    /// The value the key must have. Omit if the key must not exist.
+    #[arg(long)]
+    expected: Option<String>,
+    /// The new value of the key. Omit to remove the key.
+    #[arg(long)]
+    new: Option<String>,
+
+    #[arg(
+        long = "addr",
+        value_name = "IP:PORT",
+        default_value = "127.0.0.1:4000"
+    )]
+    addr: String,
+}
+
+#[derive(Args)]
+struct ScanCommand {
+    /// Only list keys starting with this prefix.
+    #[arg(long, conflicts_with_all = ["start", "end"])]
+    prefix: Option<String>,
+    /// The first key to list.
+    #[arg(long)]
+    start: Option<String>,
+    /// List keys before this one.
+    #[arg(long)]
+    end: Option<String>,
+
+    #[arg(
+        long = "addr",
+        value_name = "IP:PORT",
+        default_value = "127.0.0.1:4000"
+    )]
+    addr: String,
+}
+
 fn main() -> Result<()> {
     let args = Arg::parse();
 
     match args.command {
-        Commands::Get(cmd) => {
-            let mut stream = TcpStream::connect(cmd.addr)?;
-            stream.write(&serde_json::to_vec(&Message::Get { key: cmd.key })?)?;
-            let mut msg = vec![0; 128];
-            let len = stream.read(&mut msg)?;
-            println!("{}", std::str::from_utf8(&msg[..len])?);
-        }
+        Commands::Get(cmd) => match KvsClient::connect(cmd.addr)?.get(cmd.key)? {
+            Some(val) => println!("{}", val),
+            None => println!("Key not found"),
+        },
         Commands::Set(cmd) => {
-            let mut stream = TcpStream::connect(cmd.addr)?;
-            stream.write(&serde_json::to_vec(&Message::Set {
-                key: cmd.key,
-                val: cmd.val,
-            })?)?;
+            let mut client = KvsClient::connect(cmd.addr)?;
+            match cmd.ttl {
+                Some(secs) => client.set_with_ttl(cmd.key, cmd.val, Duration::from_secs(secs))?,
+                None => client.set(cmd.key, cmd.val)?,
+            }
         }
-        Commands::Rm(cmd) => {
-            let mut stream = TcpStream::connect(cmd.addr)?;
-            stream.write(&serde_json::to_vec(&Message::Rm { key: cmd.key })?)?;
-            let mut msg = vec![0; 128];
-            let len = stream.read(&mut msg)?;
-            if len > 0 {
-                eprintln!("{}", std::str::from_utf8(&msg[..len])?);
+        Commands::Rm(cmd) => match KvsClient::connect(cmd.addr)?.remove(cmd.key) {
+            Ok(()) => {}
+            Err(ErrorKind::KeyNotFound) => {
+                eprintln!("Key not found");
                 std::process::exit(1);
             }
+            Err(e) => return Err(e),
+        },
+        Commands::Cas(cmd) => {
+            match KvsClient::connect(cmd.addr)?.compare_and_swap(cmd.key, cmd.expected, cmd.new) {
+                Ok(()) => {}
+                Err(ErrorKind::CompareFailed) => {
+                    eprintln!("Value mismatch");
+                    std::process::exit(1);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Commands::Scan(cmd) => {
+            let mut client = KvsClient::connect(cmd.addr)?;
+            let pairs = match cmd.prefix {
+                Some(prefix) => client.scan_prefix(prefix)?,
+                None => client.scan((
+                    cmd.start.map_or(Bound::Unbounded, Bound::Included),
+                    cmd.end.map_or(Bound::Unbounded, Bound::Excluded),
+                ))?,
+            };
+            for (key, val) in pairs {
+                println!("{}\t{}", key, val);
+            }
         }
     }
 
@@ -1,10 +1,7 @@
 use clap::{Arg, Command};
-use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
-use kvs::{ErrorKind, KvStore, KvsEngine, Logger, Message, Result, SledKvsEngine};
-use log;
+use kvs::{engines, server, ErrorKind, Logger, Result};
 use std::env::current_dir;
-use std::io::{BufRead, Read, Write};
-use std::net::{TcpListener, TcpStream};
+use std::net::TcpListener;
 
 fn main() -> Result<()> {
     Logger::init().map_err(|e| ErrorKind::Other(format!("{:?}", e)))?;
@@ -34,132 +31,49 @@ fn main() -> Result<()> {
             Arg::new("engine")
                 .long("engine")
                 .value_name("ENGINE-NAME")
-                .help("backend engine (kvs or sled)")
+                .help(format!(
+                    "backend engine ({})",
+                    engines::engine_names().join(" or ")
+                ))
                 .default_value("kvs"),
         )
+        .arg(
+            Arg::new("max-connections")
+                .long("max-connections")
+                .value_name("COUNT")
+                .help(format!(
+                    "clients served at once, others wait until one disconnects [default: {}]",
+                    server::DEFAULT_MAX_CONNECTIONS
+                ))
+                .value_parser(clap::value_parser!(usize)),
+        )
         .get_matches();
 
     let engine: &String = matches.get_one::<String>("engine").unwrap();
-    if engine != "kvs" && engine != "sled" {
-        eprintln!("wrong engine option, please use `kvs` or `sled`");
-        std::process::exit(1);
-    }
-
-    if std::path::Path::new("engine.log").exists() {
-        let f = std::fs::File::open("engine.log")?;
-        let mut reader = std::io::BufReader::new(f);
-        let mut buf = String::new();
-        reader.read_line(&mut buf)?;
-        if &buf != engine {
+    let store = match engines::open_engine(engine, current_dir()?) {
+        Ok(store) => store,
+        Err(ErrorKind::UnknownEngine(_)) => {
+            eprintln!(
+                "wrong engine option, please use one of: {}",
+                engines::engine_names().join(", ")
+            );
+            std::process::exit(1);
+        }
+        Err(ErrorKind::EngineMismatch { .. }) => {
             eprintln!("Engine option doesn't match exist one");
             std::process::exit(1);
         }
-    } else {
-        let f = std::fs::File::create("engine.log")?;
-        let mut writer = std::io::BufWriter::new(f);
-        writer.write_all(engine.as_bytes())?;
-        writer.flush()?;
-    }
+        Err(e) => return Err(e),
+    };
 
     //let addr = matches.get_one::<String>("addr").ok_or("127.0.0.1:4000")?;
     let addr = matches.get_one::<String>("addr").unwrap();
     let listener = TcpListener::bind(addr)?;
     log::info!("start kvs-server 0.1.0 at {}", addr);
 
-    let pool = SharedQueueThreadPool::new(4)?;
-
-    // `impl trait` as argument type or return type
-    if engine == "kvs" {
-        run(KvStore::open(current_dir()?)?, pool, listener)?;
-    } else {
-        run(SledKvsEngine::open(current_dir()?)?, pool, listener)?;
-    }
-    Ok(())
-
-    // use `trait objects` with a runtime cost
-    //let mut store: Box<dyn KvsEngine> = if engine == "kvs" {
-    //    Box::new(KvStore::open(current_dir()?)?)
-    //} else {
-    //    Box::new(SledKvsEngine::new())
-    //};
-
-    //loop {
-    //    let (mut socket, addr) = listener.accept()?;
-    //    log::info!("Connection from {}", addr);
-
-    //    let mut msg = vec![0; 128];
-    //    let len = socket.read(&mut msg)?;
-    //    let msg: Message = serde_json::from_slice(&msg[..len])?;
-    //    log::info!("{:?}", msg);
-
-    //    match msg {
-    //        Message::Get { key } => {
-    //            if let Some(val) = store.get(key)? {
-    //                socket.write(val.as_bytes())?;
-    //            } else {
-    //                socket.write(b"Key not found")?;
-    //            }
-    //        }
-    //        Message::Set { key, val } => {
-    //            store.set(key, val)?;
-    //        }
-    //        Message::Rm { key } => {
-    //            match store.remove(key) {
-    //                Ok(_) => {}
-    //                Err(ErrorKind::KeyNotFound) => {
-    //                    socket.write(b"Key not found")?;
-    //                }
-    //                Err(e) => return Err(e),
-    //            }
-    //        }
-    //    }
-    //}
-}
-
-// or `store: impl KvsEngine`
-fn run<T: KvsEngine + Clone, P: ThreadPool>(
-    store: T,
-    pool: P,
-    listener: TcpListener,
-) -> Result<()> {
-    loop {
-        let (socket, addr) = listener.accept()?;
-        log::info!("Connection from {}", addr);
-
-        let store = store.clone();
-        pool.spawn(move || {
-            if let Err(e) = job(store, socket) {
-                log::info!("Job error: {:?}", e);
-            }
-        });
-    }
-}
-
-fn job<T: KvsEngine + Clone>(store: T, mut socket: TcpStream) -> Result<()> {
-    let mut msg = vec![0; 128];
-    let len = socket.read(&mut msg)?;
-    let msg: Message = serde_json::from_slice(&msg[..len])?;
-    log::info!("{:?}", msg);
-
-    match msg {
-        Message::Get { key } => {
-            if let Some(val) = store.get(key)? {
-                socket.write(val.as_bytes())?;
-            } else {
-                socket.write(b"Key not found")?;
-            }
-        }
-        Message::Set { key, val } => {
-            store.set(key, val)?;
-        }
-        Message::Rm { key } => match store.remove(key) {
-            Ok(_) => {}
-            Err(ErrorKind::KeyNotFound) => {
-                socket.write(b"Key not found")?;
-            }
-            Err(e) => return Err(e),
-        },
-    }
-
-    Ok(())
+    let max_connections = matches
+        .get_one::<usize>("max-connections")
+        .copied()
+        .unwrap_or(server::DEFAULT_MAX_CONNECTIONS);
+    server::serve(listener, store, max_connections)
 }
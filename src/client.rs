@@ -0,0 +1,211 @@
+//! Client of `kvs-server`.
+use crate::{protocol, ErrorKind, Message, Response, ResponseErrorKind, Result};
+use std::io::{BufReader, BufWriter, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::ops::Bound;
+use std::thread;
+use std::time::Duration;
+
+/// One page of a scan: key/value pairs in key order and, if more pairs follow,
+/// the key the next page continues after.
+pub type ScanPage = (Vec<(String, String)>, Option<String>);
+
+/// A connection to `kvs-server` that is kept open across requests.
+pub struct KvsClient {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+}
+
+impl KvsClient {
+    /// Connect to the server at `addr`.
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        Ok(Self {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+        })
+    }
+
+    /// Get the string value of a string key.
+    ///
+    /// If the key does not exist, return None.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        match check(self.request(&Message::Get { key })?)? {
+            Response::Value(val) => Ok(val),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Set the value of a string key to a string.
+    pub fn set(&mut self, key: String, val: String) -> Result<()> {
+        match check(self.request(&Message::Set { key, val })?)? {
+            Response::Ok => Ok(()),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Set the value of a string key to a string that expires after `ttl`.
+    pub fn set_with_ttl(&mut self, key: String, val: String, ttl: Duration) -> Result<()> {
+        let ttl_ms = ttl.as_millis().try_into().unwrap_or(u64::MAX);
+        match check(self.request(&Message::SetWithTtl { key, val, ttl_ms })?)? {
+            Response::Ok => Ok(()),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Remove a given key.
+    ///
+    /// # Errors
+    ///
+    /// Return `ErrorKind::KeyNotFound` if the key does not exist.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        match check(self.request(&Message::Rm { key })?)? {
+            Response::Ok => Ok(()),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Get the value of a key.
+    ///
+    /// If the key does not exist, return None.
+    pub fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        match check(self.request(&Message::GetBytes { key })?)? {
+            Response::Bytes(val) => Ok(val),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Set the value of a key to a value.
+    pub fn set_bytes(&mut self, key: Vec<u8>, val: Vec<u8>) -> Result<()> {
+        match check(self.request(&Message::SetBytes { key, val })?)? {
+            Response::Ok => Ok(()),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Remove a given key.
+    ///
+    /// # Errors
+    ///
+    /// Return `ErrorKind::KeyNotFound` if the key does not exist.
+    pub fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        match check(self.request(&Message::RmBytes { key })?)? {
+            Response::Ok => Ok(()),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Atomically replace the value of `key` with `new` if it currently is `expected`.
+    ///
+    /// # Errors
+    ///
+    /// Return `ErrorKind::CompareFailed` if the current value is not `expected`.
+    pub fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<()> {
+        match check(self.request(&Message::Cas { key, expected, new })?)? {
+            Response::Ok => Ok(()),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Get the key/value pairs whose keys fall in `range`, in key order.
+    ///
+    /// Large results are fetched in several pages.
+    pub fn scan(&mut self, range: (Bound<String>, Bound<String>)) -> Result<Vec<(String, String)>> {
+        let (mut start, end) = range;
+        let mut all = Vec::new();
+        loop {
+            let (pairs, next) = self.scan_page(&Message::Scan {
+                start,
+                end: end.clone(),
+                limit: None,
+            })?;
+            all.extend(pairs);
+            match next {
+                Some(key) => start = Bound::Excluded(key),
+                None => return Ok(all),
+            }
+        }
+    }
+
+    /// Get the key/value pairs whose keys start with `prefix`, in key order.
+    ///
+    /// Large results are fetched in several pages.
+    pub fn scan_prefix(&mut self, prefix: String) -> Result<Vec<(String, String)>> {
+        let mut after = None;
+        let mut all = Vec::new();
+        loop {
+            let (pairs, next) = self.scan_page(&Message::ScanPrefix {
+                prefix: prefix.clone(),
+                after,
+                limit: None,
+            })?;
+            all.extend(pairs);
+            match next {
+                Some(key) => after = Some(key),
+                None => return Ok(all),
+            }
+        }
+    }
+
+    /// Send a scan request and return the page of pairs and the key the next
+    /// page continues after.
+    pub fn scan_page(&mut self, msg: &Message) -> Result<ScanPage> {
+        match check(self.request(msg)?)? {
+            Response::Pairs { pairs, next } => Ok((pairs, next)),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Send all `msgs` back-to-back without waiting, then collect their responses.
+    ///
+    /// Responses are returned in the same order as the requests. A failed request is
+    /// reported as `Response::Error` in its slot and does not abort the batch.
+    pub fn pipeline(&mut self, msgs: &[Message]) -> Result<Vec<Response>> {
+        let writer = &mut self.writer;
+        let reader = &mut self.reader;
+        // write on another thread so a large batch can't fill both socket buffers and deadlock
+        thread::scope(|s| {
+            let sender = s.spawn(move || -> Result<()> {
+                for msg in msgs {
+                    protocol::write_message(writer, msg)?;
+                }
+                writer.flush()?;
+                Ok(())
+            });
+            let resps = msgs
+                .iter()
+                .map(|_| protocol::receive(reader))
+                .collect::<Result<Vec<Response>>>();
+            sender.join().expect("pipeline sender panicked")?;
+            resps
+        })
+    }
+
+    /// Send one request and wait for its raw response.
+    pub fn request(&mut self, msg: &Message) -> Result<Response> {
+        protocol::send(&mut self.writer, msg)?;
+        protocol::receive(&mut self.reader)
+    }
+}
+
+/// Turn `Response::Error` back into the matching `ErrorKind`.
+fn check(resp: Response) -> Result<Response> {
+    match resp {
+        Response::Error { kind, message } => Err(match kind {
+            ResponseErrorKind::KeyNotFound => ErrorKind::KeyNotFound,
+            ResponseErrorKind::CompareFailed => ErrorKind::CompareFailed,
+            ResponseErrorKind::Protocol => ErrorKind::Protocol(message),
+            ResponseErrorKind::Internal => ErrorKind::Other(message),
+        }),
+        resp => Ok(resp),
+    }
+}
+
+fn unexpected(resp: Response) -> ErrorKind {
+    ErrorKind::Protocol(format!("unexpected response {:?}", resp))
+}
@@ -1,24 +1,46 @@
-use crate::{ErrorKind, KvsEngine, Result};
-use dashmap::DashMap;
-use serde::{Deserialize, Serialize};
+use self::record::{Command, LegacyCommand, BATCH_HEADER_LEN, FILE_HEADER_LEN, VERSION};
+pub use self::sync::SyncPolicy;
+use self::sync::Syncer;
+use super::{byte_range, expires_at, now_millis, BatchOp, WriteBatch};
+use crate::{ErrorKind, KvsEngine, Result, ScanIter};
+use fs2::FileExt;
 use serde_json::Deserializer;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::ffi::OsStr;
 use std::fs::{self, File, OpenOptions};
 use std::io::prelude::*;
 use std::io::{self, BufReader, BufWriter, SeekFrom};
-use std::ops::Range;
+use std::mem;
+use std::ops::{Bound, Range};
 use std::path::{Path, PathBuf};
 use std::sync::{
-    atomic::{AtomicU64, Ordering},
-    Arc, Mutex,
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc, Mutex, RwLock,
 };
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+mod fault;
+mod hint;
+mod options;
+mod record;
+mod snapshot;
+mod sync;
+
+use self::fault::FaultFile;
+pub use self::fault::{CrashPoint, FaultInjector};
+pub use self::options::{CompactionPolicy, KvStoreOptions};
+pub use self::snapshot::Snapshot;
 
 const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+const LOCK_FILE: &str = "LOCK";
+/// Keys a scan takes from the index per read lock.
+const SCAN_CHUNK: usize = 256;
 
-/// The `KvStore` stores string key/value pairs.
+/// The `KvStore` stores key/value pairs of arbitrary bytes.
 ///
-/// Key/value pairs are stored in a `HashMap` in memory and not persisted to disk.
+/// Writes are appended to log files on disk, and an in-memory `BTreeMap` index
+/// points every key to the record holding its value.
 ///
 /// Example:
 ///
@@ -32,56 +54,369 @@ const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
 /// assert_eq!(val, Some("value".to_owned()));
 /// ```
 pub struct KvStore {
-    db: Arc<DashMap<String, CommandPointer>>, // use DashMap to replace RwLock<HashMap<K, V>>
+    core: Core,
+    compactor: Arc<Compactor>,
+    // dropped after the compactor, which waits for a running compaction;
+    // read-only stores don't lock
+    _lock: Option<Arc<DirLock>>,
+}
+
+/// State shared by every handle of a store and its background compaction.
+#[derive(Clone)]
+struct Core {
+    // ordered so keys can be scanned by range
+    db: Arc<RwLock<Index>>,
+    // sequence number of the last applied write
+    seq: Arc<AtomicU64>,
+    // sequence numbers pinned by open snapshots, with the number of snapshots
+    // pinning each; locked while writes are applied to the index
+    snapshots: Arc<Mutex<BTreeMap<u64, usize>>>,
     path: Arc<PathBuf>,
-    fname: AtomicU64,
-    trash: AtomicU64,
-    writer: Arc<Mutex<BufWriterWithPos<File>>>,
+    fname: Arc<AtomicU64>,
+    trash: Arc<AtomicU64>,
+    // `None` if the store is read-only
+    writer: Arc<Mutex<Option<BufWriterWithPos<FaultFile>>>>,
     readers: Arc<Mutex<HashMap<u64, BufReaderWithPos<File>>>>,
+    syncer: Option<Arc<Syncer>>,
+    queue: Arc<Mutex<Vec<Arc<PendingWrite>>>>,
+    // total bytes of all log files
+    size: Arc<AtomicU64>,
+    options: Arc<KvStoreOptions>,
+    stats: Arc<Stats>,
+    // held for the whole compaction so only one runs at a time
+    compaction: Arc<Mutex<()>>,
+}
+
+/// Statistics of the compactions run by a `KvStore`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CompactionStats {
+    /// Number of finished compactions.
+    pub compactions: u64,
+    /// Bytes of log files freed by compactions.
+    pub reclaimed_bytes: u64,
+}
+
+#[derive(Default)]
+struct Stats {
+    compactions: AtomicU64,
+    reclaimed_bytes: AtomicU64,
 }
 
 impl KvsEngine for KvStore {
-    fn set(&self, key: String, val: String) -> Result<()> {
-        let command = Command::Set {
-            key: key.clone(),
-            val: val,
+    fn set_bytes(&self, key: Vec<u8>, val: Vec<u8>) -> Result<()> {
+        self.core.commit(Command::Set {
+            key,
+            val,
+            expires_at: None,
+        })?;
+        self.maybe_compact();
+        Ok(())
+    }
+
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.core.get(&key)
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        self.core.commit(Command::Remove { key })?;
+        self.maybe_compact();
+        Ok(())
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let cmds = batch
+            .ops
+            .into_iter()
+            .map(|op| match op {
+                BatchOp::Put { key, val } => Command::Set {
+                    key: key.into_bytes(),
+                    val: val.into_bytes(),
+                    expires_at: None,
+                },
+                BatchOp::Delete { key } => Command::Remove {
+                    key: key.into_bytes(),
+                },
+            })
+            .collect();
+        self.core.commit(Command::Batch(cmds))?;
+        self.maybe_compact();
+        Ok(())
+    }
+
+    fn set_with_ttl(&self, key: String, val: String, ttl: Duration) -> Result<()> {
+        self.core.commit(Command::Set {
+            key: key.into_bytes(),
+            val: val.into_bytes(),
+            expires_at: Some(expires_at(ttl)),
+        })?;
+        self.maybe_compact();
+        Ok(())
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<()> {
+        let key = key.into_bytes();
+        let cmd = match new {
+            Some(val) => Command::Set {
+                key,
+                val: val.into_bytes(),
+                expires_at: None,
+            },
+            // removing a missing key writes nothing, so there is nothing to commit
+            None if expected.is_none() => {
+                return match self.core.get(&key)? {
+                    None => Ok(()),
+                    Some(_) => Err(ErrorKind::CompareFailed),
+                }
+            }
+            None => Command::Remove { key },
         };
-        let mut writer = self.writer.lock().unwrap();
-        let pos = writer.pos;
-        //write!(writer, "{}", serde_json::to_string(&command)?)?;
-        serde_json::to_writer(&mut *writer, &command)?;
-        //writer.write(serde_json::to_vec(&command)?.as_slice())?;
-        writer.flush()?;
-        let new_pos = writer.pos;
-        drop(writer);
+        self.core
+            .commit_if(cmd, Some(expected.map(String::into_bytes)))?;
+        self.maybe_compact();
+        Ok(())
+    }
 
-        let mut trash = self.trash.load(Ordering::SeqCst);
-        if let Some(old_cmd) = self.db.insert(
-            key,
-            (self.fname.load(Ordering::SeqCst), pos..new_pos).into(),
-        ) {
-            trash += old_cmd.len;
-            self.trash.store(trash, Ordering::SeqCst);
+    fn scan(&self, range: (Bound<String>, Bound<String>)) -> Result<ScanIter> {
+        Ok(self.core.scan(range, None))
+    }
+}
+
+impl KvStore {
+    /// Open the KvStore at a given path with default options. Return the KvStore.
+    ///
+    /// Logs written in the legacy JSON format are rewritten into the binary format.
+    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStoreOptions::new().open(path)
+    }
+
+    /// Open the KvStore at a given path, syncing writes to disk according to `policy`.
+    pub fn open_with_sync(path: impl Into<PathBuf>, policy: SyncPolicy) -> Result<KvStore> {
+        KvStoreOptions::new().sync(policy).open(path)
+    }
+
+    /// Open the KvStore at a given path for reading only.
+    ///
+    /// No file is created, changed or removed, and the directory is not locked, so
+    /// this works on a copy of the store as well as next to a writer. The store
+    /// sees the data as of opening. Legacy logs are not migrated and fail to open.
+    ///
+    /// # Errors
+    ///
+    /// Writes and compactions of the returned store fail with `ErrorKind::ReadOnly`.
+    pub fn open_read_only(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStoreOptions::new()
+            .create_if_missing(false)
+            .read_only(true)
+            .open(path)
+    }
+
+    fn open_with_options(path: PathBuf, options: &KvStoreOptions) -> Result<KvStore> {
+        options.validate()?;
+        let read_only = options.read_only;
+        if options.create_if_missing {
+            fs::create_dir_all(&path)?;
+        } else if !path.is_dir() {
+            return Err(ErrorKind::NotFound(path));
         }
-        //drop(db); // if not explictly drop here, `compaction` test will fail
+        let (lock, list) = if read_only {
+            (None, sorted_gen_list(&path)?)
+        } else {
+            let lock = DirLock::acquire(&path)?;
+            remove_unfinished_compactions(&path)?;
+            let list = migrate_legacy_logs(&path, sorted_gen_list(&path)?, options)?;
+            (Some(Arc::new(lock)), list)
+        };
+        let mut db = BTreeMap::new();
+        let mut readers = HashMap::new();
+        let mut trash = 0;
+        let mut size = 0;
 
-        if trash >= COMPACTION_THRESHOLD {
-            self.compact()?;
+        // only the newest log may end with a torn write, older ones were sealed
+        let active = list.last().copied();
+        for &fname in &list {
+            let file = path.join(format!("{}.log", fname));
+            let f = File::open(&file)?;
+            // compacted logs come with hints, so their values need not be read
+            if let Some(hints) = hint::read_hints(&path.join(format!("{}.hint", fname)))? {
+                let now = now_millis();
+                for hint in hints {
+                    let version = Version {
+                        seq: 0,
+                        cmd: CommandPointer {
+                            fname,
+                            pos: hint.pos,
+                            len: hint.len,
+                            expires_at: hint.expires_at,
+                        },
+                        removed: false,
+                    };
+                    trash += insert_version(&mut db, hint.key, version, &[], now);
+                }
+                size += f.metadata()?.len();
+                readers.insert(
+                    fname,
+                    BufReaderWithPos::with_capacity(options.read_buffer_size, f),
+                );
+                continue;
+            }
+            let mut reader = BufReader::new(&f);
+            if record::read_file_header(&mut reader)? != Some(VERSION) {
+                return Err(ErrorKind::Other(format!(
+                    "unsupported format of {}.log",
+                    fname
+                )));
+            }
+            let mut pos = FILE_HEADER_LEN;
+            loop {
+                let (cmd, len) = match Command::read_from(&mut reader, VERSION) {
+                    Ok(Some(rec)) => rec,
+                    Ok(None) => break,
+                    Err(e) if is_damaged(&e) => {
+                        // the header is checked before its lengths are used, so
+                        // a record cut off at the end can only be a torn write
+                        let cut_off = !matches!(e, ErrorKind::ReadFail);
+                        if cut_off && Some(fname) == active && reader.fill_buf()?.is_empty() {
+                            if read_only {
+                                // the record may still be being written by a writer
+                                break;
+                            }
+                            // the last record was never acknowledged, drop it
+                            log::warn!("truncating torn write at {}:{}", file.display(), pos);
+                            OpenOptions::new().write(true).open(&file)?.set_len(pos)?;
+                            break;
+                        }
+                        return Err(ErrorKind::Corruption { file, offset: pos });
+                    }
+                    Err(e) => return Err(e),
+                };
+                let new_pos = pos + len;
+                trash += index_command(&mut db, &cmd, fname, pos..new_pos, 0, &[]);
+                pos = new_pos;
+            }
+            size += pos;
+            readers.insert(
+                fname,
+                BufReaderWithPos::with_capacity(options.read_buffer_size, f),
+            );
         }
-        Ok(())
+
+        let mut fname = *list.last().unwrap_or(&0);
+        let (writer, syncer) = if read_only {
+            (None, None)
+        } else {
+            fname += 1;
+            let writer = new_log_file(&path, fname, &mut readers, options)?;
+            size += FILE_HEADER_LEN;
+            let syncer = Syncer::new(options.sync, writer.writer.get_ref().try_clone()?);
+            (Some(writer), Some(syncer))
+        };
+
+        let core = Core {
+            db: Arc::new(RwLock::new(db)),
+            seq: Arc::new(AtomicU64::new(0)),
+            snapshots: Arc::new(Mutex::new(BTreeMap::new())),
+            path: Arc::new(path),
+            fname: Arc::new(AtomicU64::new(fname)),
+            trash: Arc::new(AtomicU64::new(trash)),
+            syncer,
+            writer: Arc::new(Mutex::new(writer)),
+            readers: Arc::new(Mutex::new(readers)),
+            queue: Arc::new(Mutex::new(Vec::new())),
+            size: Arc::new(AtomicU64::new(size)),
+            options: Arc::new(options.clone()),
+            stats: Arc::new(Stats::default()),
+            compaction: Arc::new(Mutex::new(())),
+        };
+        Ok(KvStore {
+            core,
+            compactor: Arc::new(Compactor::default()),
+            _lock: lock,
+        })
+    }
+
+    /// Compact the log files, waiting until the compaction is done.
+    ///
+    /// Compaction normally runs in the background once enough stale data piles up.
+    pub fn compact(&self) -> Result<()> {
+        self.core.compact()
+    }
+
+    /// Take a point-in-time view of the store.
+    ///
+    /// The snapshot sees every write applied before it was taken and none after,
+    /// while writers carry on. The versions it sees are kept in the index and by
+    /// compactions until it is dropped. Keys with a TTL still expire in it.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot::new(&self.core)
+    }
+
+    /// Statistics of the compactions run so far.
+    pub fn compaction_stats(&self) -> CompactionStats {
+        CompactionStats {
+            compactions: self.core.stats.compactions.load(Ordering::SeqCst),
+            reclaimed_bytes: self.core.stats.reclaimed_bytes.load(Ordering::SeqCst),
+        }
+    }
+
+    /// Start a background compaction if the compaction policy asks for one.
+    fn maybe_compact(&self) {
+        if self.core.should_compact() {
+            self.compactor.spawn(&self.core);
+        }
+    }
+}
+
+impl Core {
+    fn should_compact(&self) -> bool {
+        let trash = self.trash.load(Ordering::SeqCst);
+        match self.options.compaction {
+            CompactionPolicy::StaleBytes(threshold) => trash >= threshold,
+            CompactionPolicy::StaleRatio {
+                ratio,
+                min_stale_bytes,
+            } => {
+                trash >= min_stale_bytes
+                    && trash as f64 >= ratio * self.size.load(Ordering::SeqCst) as f64
+            }
+            CompactionPolicy::MaxLogFiles(max) => self.readers.lock().unwrap().len() > max,
+            CompactionPolicy::Manual => false,
+        }
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.get_at(key, None)
     }
 
-    fn get(&self, key: String) -> Result<Option<String>> {
-        if let Some(rec) = self.db.get(&key) {
-            //let f = File::open(&self.path.join(format!("{}.log", rec.fname.to_string())))?;
-            //let mut reader = BufReader::new(f);
-            let mut readers = self.readers.lock().unwrap();
+    /// Get the value of `key` as of sequence number `seq`, or the latest value.
+    fn get_at(&self, key: &[u8], seq: Option<u64>) -> Result<Option<Vec<u8>>> {
+        // readers are locked first, so compaction can't remove the log the index
+        // points to before it is read
+        let mut readers = self.readers.lock().unwrap();
+        let rec = self
+            .db
+            .read()
+            .unwrap()
+            .get(key)
+            .and_then(|versions| visible(versions, seq))
+            .filter(|version| !version.removed)
+            .map(|version| version.cmd.clone());
+        if let Some(rec) = rec.filter(|rec| !rec.is_expired(now_millis())) {
             let reader = readers
                 .get_mut(&rec.fname)
                 .expect("Could not open log reader"); // will make `&self -> &mut self`
             reader.seek(SeekFrom::Start(rec.pos))?;
-            let cmd_reader = reader.take(rec.len);
-            if let Command::Set { val, .. } = serde_json::from_reader(cmd_reader)? {
+            let mut buf = vec![0; rec.len as usize];
+            reader.read_exact(&mut buf)?;
+            let cmd = Command::decode(&buf).map_err(|_| ErrorKind::Corruption {
+                file: self.path.join(format!("{}.log", rec.fname)),
+                offset: rec.pos,
+            })?;
+            if let Command::Set { val, .. } = cmd {
                 Ok(Some(val))
             } else {
                 Err(ErrorKind::ReadFail)
@@ -91,132 +426,565 @@ impl KvsEngine for KvStore {
         }
     }
 
-    fn remove(&self, key: String) -> Result<()> {
-        if let Some((_, cmd)) = self.db.remove(&key) {
-            let command = Command::Remove { key: key };
-            let mut writer = self.writer.lock().unwrap();
-            write!(writer, "{}", serde_json::to_string(&command)?)?;
-            writer.flush()?;
+    /// Iterate over the values of the keys in `range` as of sequence number `seq`,
+    /// or the latest values.
+    fn scan(&self, range: (Bound<String>, Bound<String>), seq: Option<u64>) -> ScanIter {
+        let Some((mut start, end)) = byte_range(range) else {
+            return Box::new(std::iter::empty());
+        };
+        // the index is walked a chunk of keys at a time, restarting after the
+        // last one, so writers don't wait for the whole range to be read
+        let core = self.clone();
+        let mut keys = Vec::<String>::new().into_iter();
+        let mut done = false;
+        Box::new(std::iter::from_fn(move || loop {
+            if let Some(key) = keys.next() {
+                // values are read lazily, skipping keys removed in the meantime
+                let val = match core.get_at(key.as_bytes(), seq) {
+                    Ok(Some(val)) => val,
+                    Ok(None) => continue,
+                    Err(e) => return Some(Err(e)),
+                };
+                return Some(
+                    String::from_utf8(val)
+                        .map(|val| (key, val))
+                        .map_err(Into::into),
+                );
+            }
+            if done {
+                return None;
+            }
+            let chunk: Vec<Vec<u8>> = core
+                .db
+                .read()
+                .unwrap()
+                .range((start.clone(), end.clone()))
+                .take(SCAN_CHUNK)
+                .map(|(key, _)| key.clone())
+                .collect();
+            done = chunk.len() < SCAN_CHUNK;
+            start = Bound::Excluded(chunk.last()?.clone());
+            keys = chunk
+                .into_iter()
+                .filter_map(|key| String::from_utf8(key).ok())
+                .collect::<Vec<_>>()
+                .into_iter();
+        }))
+    }
 
-            let trash = self.trash.load(Ordering::SeqCst) + cmd.len;
-            self.trash.store(trash, Ordering::SeqCst);
-            if trash >= COMPACTION_THRESHOLD {
-                self.compact()?;
+    /// Rewrite the live records of all sealed logs into a single log.
+    ///
+    /// The active log is sealed first and new writes go to a fresh log numbered
+    /// after the compacted one, so writers only wait for that switch. Readers are
+    /// only locked to copy single records and to swap in the compacted log.
+    fn compact(&self) -> Result<()> {
+        let syncer = self.syncer.as_ref().ok_or(ErrorKind::ReadOnly)?;
+        let _compaction = self.compaction.lock().unwrap();
+
+        // seal the active log
+        let mut writer = self.writer.lock().unwrap();
+        if writer.is_none() {
+            return Err(ErrorKind::Poisoned);
+        }
+        let sealed = self.fname.load(Ordering::SeqCst);
+        let compact_fname = sealed + 1;
+        let active = new_log_file(
+            &self.path,
+            sealed + 2,
+            &mut self.readers.lock().unwrap(),
+            &self.options,
+        )?;
+        syncer.rotate(active.writer.get_ref().try_clone()?)?;
+        *writer = Some(active);
+        self.size.fetch_add(FILE_HEADER_LEN, Ordering::SeqCst);
+        self.fname.store(sealed + 2, Ordering::SeqCst);
+        self.trash.store(0, Ordering::SeqCst);
+        drop(writer);
+
+        // copy the versions in sealed logs that the latest state or an open
+        // snapshot can still see, dropping expired ones; a snapshot taken later
+        // only sees versions at least as new as the ones kept here
+        let now = now_millis();
+        let pinned = self.pinned();
+        let mut live: Vec<(Vec<u8>, Version, bool)> = Vec::new();
+        for (key, versions) in self.db.read().unwrap().iter() {
+            let mut kept = versions.clone();
+            prune(&mut kept, &pinned, now);
+            for version in versions
+                .iter()
+                .filter(|version| version.cmd.fname <= sealed)
+            {
+                let keep = kept.iter().any(|kept| kept.is_at(&version.cmd));
+                live.push((key.clone(), version.clone(), keep));
             }
-            Ok(())
-        } else {
-            Err(ErrorKind::KeyNotFound)
         }
+        // written under a temporary name so a crash never leaves a partial log behind
+        let compact_path = self.path.join(format!("{}.log", compact_fname));
+        let tmp_path = compact_path.with_extension("compact");
+        let mut writer = BufWriterWithPos::with_capacity(
+            self.options.write_buffer_size,
+            FaultFile::new(File::create(&tmp_path)?, self.options.faults.clone()),
+        );
+        record::write_file_header(&mut writer)?;
+        // versions of a key are copied oldest first, so replaying the compacted
+        // log ends with the latest one
+        let mut moved: Vec<(Vec<u8>, Version, Option<CommandPointer>)> =
+            Vec::with_capacity(live.len());
+        for (key, old, keep) in live {
+            if !keep {
+                moved.push((key, old, None));
+                continue;
+            }
+            let mut buf = vec![0; old.cmd.len as usize];
+            {
+                let mut readers = self.readers.lock().unwrap();
+                let reader = readers
+                    .get_mut(&old.cmd.fname)
+                    .expect("Could not open log reader");
+                reader.seek(SeekFrom::Start(old.cmd.pos))?;
+                reader.read_exact(&mut buf)?;
+            }
+            let pos = writer.pos;
+            writer.write_all(&buf)?;
+            let new_cmd = CommandPointer {
+                fname: compact_fname,
+                pos,
+                len: writer.pos - pos,
+                expires_at: old.cmd.expires_at,
+            };
+            moved.push((key, old, Some(new_cmd)));
+        }
+        writer.flush()?;
+        // stale logs are removed below, so the compacted one must be on disk first
+        writer.writer.get_ref().sync_data()?;
+        self.reach(CrashPoint::CompactedLogWritten)?;
+        fs::rename(&tmp_path, &compact_path)?;
+        sync_dir(&self.path)?;
+        self.reach(CrashPoint::CompactedLogRenamed)?;
+        // hints only describe the last copied version of each key
+        let mut last: BTreeMap<&[u8], (&Version, &CommandPointer)> = BTreeMap::new();
+        for (key, old, new_cmd) in &moved {
+            if let Some(new_cmd) = new_cmd {
+                last.insert(key.as_slice(), (old, new_cmd));
+            }
+        }
+        hint::write_hints(
+            &self.path.join(format!("{}.hint", compact_fname)),
+            last.into_iter()
+                .filter(|(_, (old, _))| !old.removed)
+                .map(|(key, (_, cmd))| (key, cmd.pos, cmd.len, cmd.expires_at)),
+        )?;
+        self.reach(CrashPoint::HintsWritten)?;
+
+        // swap in the compacted log, skipping versions dropped by writes since
+        self.readers.lock().unwrap().insert(
+            compact_fname,
+            BufReaderWithPos::with_capacity(
+                self.options.read_buffer_size,
+                File::open(&compact_path)?,
+            ),
+        );
+        let mut db = self.db.write().unwrap();
+        for (key, old, new_cmd) in moved {
+            let Some(versions) = db.get_mut(&key) else {
+                continue;
+            };
+            if let Some(i) = versions.iter().position(|version| version.is_at(&old.cmd)) {
+                match new_cmd {
+                    Some(new_cmd) => versions[i].cmd = new_cmd,
+                    None => {
+                        versions.remove(i);
+                    }
+                }
+                trim(versions, now);
+                if versions.is_empty() {
+                    db.remove(&key);
+                }
+            }
+        }
+        drop(db);
+
+        // remove stale logs
+        let mut stale = Vec::new();
+        self.readers.lock().unwrap().retain(|&fname, _| {
+            if fname <= sealed {
+                stale.push(fname);
+            }
+            fname > sealed
+        });
+        // oldest first: a crash in between must not leave a value behind without
+        // the newer log removing it
+        stale.sort_unstable();
+        let mut reclaimed = 0;
+        for fname in stale {
+            let file = self.path.join(format!("{}.log", fname));
+            reclaimed += fs::metadata(&file)?.len();
+            fs::remove_file(file)?;
+            self.reach(CrashPoint::StaleLogRemoved)?;
+            let hint = self.path.join(format!("{}.hint", fname));
+            if hint.exists() {
+                fs::remove_file(hint)?;
+            }
+        }
+        let compacted = writer.pos;
+        self.size.fetch_add(compacted, Ordering::SeqCst);
+        self.size.fetch_sub(reclaimed, Ordering::SeqCst);
+        self.stats.compactions.fetch_add(1, Ordering::SeqCst);
+        self.stats
+            .reclaimed_bytes
+            .fetch_add(reclaimed.saturating_sub(compacted), Ordering::SeqCst);
+        Ok(())
     }
-}
 
-impl KvStore {
-    /// Open the KvStore at a given path. Return the KvStore.
-    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
-        let path = path.into();
+    /// Append `cmd` to the log through the group commit queue.
+    ///
+    /// The caller queues its command and waits for the writer lock. Whoever gets
+    /// the lock first becomes the leader: it writes every queued command with a
+    /// single flush (and `fsync` if the sync policy asks for one), updates the
+    /// index in log order and hands each queued caller its result. Callers whose
+    /// command was committed by a leader return as soon as they get the lock.
+    fn commit(&self, cmd: Command) -> Result<()> {
+        self.commit_if(cmd, None)
+    }
 
-        let list = sorted_gen_list(&path)?;
-        let db: DashMap<String, CommandPointer> = DashMap::new();
-        let mut readers = HashMap::new();
-        let mut trash = 0;
+    /// Like `commit`, but if `expected` is given, only write `cmd` if its key
+    /// currently has the expected value.
+    ///
+    /// # Errors
+    ///
+    /// Return `ErrorKind::CompareFailed` if the value doesn't match.
+    fn commit_if(&self, cmd: Command, expected: Option<Option<Vec<u8>>>) -> Result<()> {
+        let syncer = self.syncer.as_ref().ok_or(ErrorKind::ReadOnly)?;
+        let pending = Arc::new(PendingWrite {
+            cmd,
+            expected,
+            done: Mutex::new(None),
+        });
+        self.queue.lock().unwrap().push(Arc::clone(&pending));
 
-        for &fname in &list {
-            let f = File::open(&path.join(format!("{}.log", fname)))?;
-            let reader = BufReader::new(&f);
-            let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
-            let mut pos = 0;
-            while let Some(Ok(cmd)) = stream.next() {
-                let new_pos = stream.byte_offset() as u64;
-                match cmd {
-                    Command::Set { key, .. } => {
-                        if let Some(old_cmd) = db.insert(key, (fname, pos..new_pos).into()) {
-                            trash += old_cmd.len;
-                        }
+        let mut writer = self.writer.lock().unwrap();
+        if let Some(res) = pending.done.lock().unwrap().take() {
+            return res;
+        }
+        let batch = mem::take(&mut *self.queue.lock().unwrap());
+        let fname = self.fname.load(Ordering::SeqCst);
+        let active = writer.as_mut().ok_or(ErrorKind::Poisoned)?;
+        let start = active.pos;
+        match self.write_queued(active, syncer, &batch) {
+            Ok(ranges) => {
+                // snapshots are taken between groups, never in the middle of one
+                let snapshots = self.snapshots.lock().unwrap();
+                let pinned: Vec<u64> = snapshots.keys().copied().collect();
+                for (write, range) in batch.iter().zip(ranges) {
+                    let res = range.map(|range| self.apply(&write.cmd, fname, range, &pinned));
+                    *write.done.lock().unwrap() = Some(res);
+                }
+                drop(snapshots);
+                drop(writer);
+                let res = pending.done.lock().unwrap().take();
+                res.expect("leader's own write is in its batch")
+            }
+            Err(e) => {
+                // the records of the group may be partly on disk or still
+                // buffered; drop them, or a later flush would persist writes
+                // reported as failed
+                let active = writer.take().expect("checked above");
+                match active.truncate(start) {
+                    Ok(active) => *writer = Some(active),
+                    Err(err) => log::error!("Could not roll back failed write: {:?}", err),
+                }
+                for write in &batch {
+                    *write.done.lock().unwrap() = Some(Err(ErrorKind::Other(format!(
+                        "group commit failed: {:?}",
+                        e
+                    ))));
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Write a batch of queued commands and make them durable.
+    ///
+    /// Return the position of each command in the active log, or the error of a
+    /// command that is not written: a `Remove` of a key that doesn't exist or a
+    /// compare-and-swap whose expected value doesn't match.
+    fn write_queued(
+        &self,
+        writer: &mut BufWriterWithPos<FaultFile>,
+        syncer: &Syncer,
+        batch: &[Arc<PendingWrite>],
+    ) -> Result<Vec<Result<Range<u64>>>> {
+        let now = now_millis();
+        // value of a key after the commands already in this batch
+        let mut latest: HashMap<&[u8], Option<&[u8]>> = HashMap::new();
+        let mut ranges = Vec::with_capacity(batch.len());
+        for write in batch {
+            if let Some(expected) = &write.expected {
+                let key = match &write.cmd {
+                    Command::Set { key, .. } | Command::Remove { key } => key.as_slice(),
+                    Command::Batch(_) => unreachable!("batches are never compared"),
+                };
+                let current = match latest.get(key) {
+                    Some(val) => Ok(val.map(<[u8]>::to_vec)),
+                    None => self.get(key),
+                };
+                match current {
+                    Ok(current) if current == *expected => {}
+                    Ok(_) => {
+                        ranges.push(Err(ErrorKind::CompareFailed));
+                        continue;
                     }
-                    Command::Remove { key } => {
-                        if let Some((_, old_cmd)) = db.remove(&key) {
-                            trash += old_cmd.len;
-                        };
-                        trash += new_pos - pos;
+                    Err(e) => {
+                        ranges.push(Err(e));
+                        continue;
                     }
                 }
-                pos = new_pos;
+            } else if let Command::Remove { key } = &write.cmd {
+                let found = latest.get(key.as_slice()).map(Option::is_some);
+                let live = |versions: &Vec<Version>| {
+                    visible(versions, None).is_some_and(|v| v.is_live(now))
+                };
+                if !found.unwrap_or_else(|| self.db.read().unwrap().get(key).is_some_and(live)) {
+                    ranges.push(Err(ErrorKind::KeyNotFound));
+                    continue;
+                }
+            }
+            let pos = writer.pos;
+            writer.write_all(&write.cmd.encode())?;
+            ranges.push(Ok(pos..writer.pos));
+            let cmds = match &write.cmd {
+                Command::Batch(cmds) => cmds.as_slice(),
+                cmd => std::slice::from_ref(cmd),
+            };
+            for cmd in cmds {
+                match cmd {
+                    Command::Set { key, val, .. } => latest.insert(key, Some(val)),
+                    Command::Remove { key } => latest.insert(key, None),
+                    Command::Batch(_) => unreachable!("batches are never nested"),
+                };
             }
-            readers.insert(fname, BufReaderWithPos::new(f));
         }
+        writer.flush()?;
+        let seq = syncer.written(batch.len() as u64);
+        syncer.commit(seq)?;
+        Ok(ranges)
+    }
 
-        let fname = list.last().unwrap_or(&0) + 1;
-        let writer = new_log_file(&path, fname, &mut readers)?;
+    /// Update the index for a command written at `range` of log `fname`, as the
+    /// next sequence number. `pinned` are the sequence numbers of open snapshots.
+    fn apply(&self, cmd: &Command, fname: u64, range: Range<u64>, pinned: &[u64]) {
+        self.size
+            .fetch_add(range.end - range.start, Ordering::SeqCst);
+        let seq = self.seq.load(Ordering::SeqCst) + 1;
+        let trash = index_command(
+            &mut self.db.write().unwrap(),
+            cmd,
+            fname,
+            range,
+            seq,
+            pinned,
+        );
+        self.seq.store(seq, Ordering::SeqCst);
+        self.trash.fetch_add(trash, Ordering::SeqCst);
+    }
 
-        Ok(KvStore {
-            db: Arc::new(db),
-            path: Arc::new(path),
-            fname: AtomicU64::new(fname),
-            trash: AtomicU64::new(trash),
-            writer: Arc::new(Mutex::new(writer)),
-            readers: Arc::new(Mutex::new(readers)),
-        })
+    /// Fail as if the process died at `point`, if a fault injector asks for it.
+    fn reach(&self, point: CrashPoint) -> io::Result<()> {
+        match &self.options.faults {
+            Some(faults) => faults.reach(point),
+            None => Ok(()),
+        }
     }
 
-    /// Compact log file.
-    pub fn compact(&self) -> Result<()> {
-        println!("Compaction start");
-        let old_fname = self.fname.load(Ordering::SeqCst);
-        // compact file
-        let compact_fname = old_fname + 1;
-        let mut readers = HashMap::new();
-        let mut writer = new_log_file(&self.path, compact_fname, &mut readers)?;
-        let mut pos = 0;
-        let mut old_readers = self.readers.lock().unwrap();
-        for mut cmd_pointer in self.db.iter_mut() {
-            let reader = old_readers
-                .get_mut(&cmd_pointer.fname)
-                .expect("Could not open log reader");
-            if reader.pos != cmd_pointer.pos {
-                reader.seek(SeekFrom::Start(cmd_pointer.pos))?;
+    /// Sequence numbers pinned by open snapshots, in ascending order.
+    fn pinned(&self) -> Vec<u64> {
+        self.snapshots.lock().unwrap().keys().copied().collect()
+    }
+}
+
+/// Versions of every key, oldest first.
+type Index = BTreeMap<Vec<u8>, Vec<Version>>;
+
+/// Update `db` for `cmd` written at `range` of log `fname` as sequence number
+/// `seq` and return the number of bytes it made stale.
+fn index_command(
+    db: &mut Index,
+    cmd: &Command,
+    fname: u64,
+    range: Range<u64>,
+    seq: u64,
+    pinned: &[u64],
+) -> u64 {
+    let (key, version) = match cmd {
+        Command::Set {
+            key, expires_at, ..
+        } => {
+            let cmd = CommandPointer {
+                expires_at: *expires_at,
+                ..(fname, range).into()
+            };
+            let version = Version {
+                seq,
+                cmd,
+                removed: false,
+            };
+            (key, version)
+        }
+        Command::Remove { key } => {
+            let version = Version {
+                seq,
+                cmd: (fname, range).into(),
+                removed: true,
+            };
+            (key, version)
+        }
+        Command::Batch(cmds) => {
+            // the index points into the batch, so its header is never read again
+            let mut trash = BATCH_HEADER_LEN;
+            let mut pos = range.start + BATCH_HEADER_LEN;
+            for cmd in cmds {
+                let end = pos + cmd.encoded_len();
+                trash += index_command(db, cmd, fname, pos..end, seq, pinned);
+                pos = end;
             }
-            let mut cmd_reader = reader.take(cmd_pointer.len);
-            let len = io::copy(&mut cmd_reader, &mut writer)?;
-            cmd_pointer.fname = compact_fname;
-            cmd_pointer.pos = pos;
-            pos += len as u64;
+            return trash;
         }
-        writer.flush()?;
+    };
+    insert_version(db, key.clone(), version, pinned, now_millis())
+}
 
-        // new writer
-        self.fname.store(old_fname + 2, Ordering::SeqCst);
-        *self.writer.lock().unwrap() = new_log_file(&self.path, old_fname + 2, &mut readers)?;
-
-        //drop(old_readers); // program will stuck without this line, stupid mistake
-        //// remove stale log
-        //for fname in self.readers.lock().unwrap().keys() {
-        //    fs::remove_file(self.path.join(format!("{}.log", fname)))?;
-        //}
-        //*self.readers.lock().unwrap() = readers;
-        // remove stale log
-        for fname in old_readers.keys() {
-            println!("removing {}", fname);
-            fs::remove_file(self.path.join(format!("{}.log", fname)))?;
-        }
-        *old_readers = readers;
-        self.trash.store(0, Ordering::SeqCst);
-        Ok(())
+/// Add the newest version of `key` to `db`, drop the versions nobody can see
+/// anymore and return their number of bytes.
+fn insert_version(db: &mut Index, key: Vec<u8>, version: Version, pinned: &[u64], now: u64) -> u64 {
+    let mut versions = db.remove(&key).unwrap_or_default();
+    versions.push(version);
+    let trash = prune(&mut versions, pinned, now);
+    if !versions.is_empty() {
+        db.insert(key, versions);
     }
+    trash
+}
+
+/// Newest of `versions` as of sequence number `seq`, or the newest of all.
+fn visible(versions: &[Version], seq: Option<u64>) -> Option<&Version> {
+    match seq {
+        Some(seq) => versions.iter().rev().find(|version| version.seq <= seq),
+        None => versions.last(),
+    }
+}
+
+/// Keep only the newest version and the ones seen by snapshots pinned at
+/// `pinned`. Return the number of bytes of the dropped versions.
+fn prune(versions: &mut Vec<Version>, pinned: &[u64], now: u64) -> u64 {
+    let mut keep = vec![false; versions.len()];
+    if let Some(last) = keep.last_mut() {
+        *last = true;
+    }
+    for &seq in pinned {
+        if let Some(i) = versions.iter().rposition(|version| version.seq <= seq) {
+            keep[i] = true;
+        }
+    }
+    let mut trash = 0;
+    let mut keep = keep.into_iter();
+    versions.retain(|version| {
+        let keep = keep.next().unwrap();
+        if !keep {
+            trash += version.cmd.len;
+        }
+        keep
+    });
+    trash + trim(versions, now)
+}
+
+/// Drop the oldest versions as long as they read as a missing key, which is what
+/// a reader finds without them too. Return their number of bytes.
+fn trim(versions: &mut Vec<Version>, now: u64) -> u64 {
+    let dead = versions
+        .iter()
+        .take_while(|version| !version.is_live(now))
+        .count();
+    versions.drain(..dead).map(|version| version.cmd.len).sum()
 }
 
 impl Clone for KvStore {
     fn clone(&self) -> Self {
         Self {
-            db: Arc::clone(&self.db),
-            path: Arc::clone(&self.path),
-            fname: AtomicU64::new(self.fname.load(Ordering::SeqCst)),
-            trash: AtomicU64::new(self.trash.load(Ordering::SeqCst)),
-            writer: Arc::clone(&self.writer),
-            readers: Arc::clone(&self.readers),
+            core: self.core.clone(),
+            compactor: Arc::clone(&self.compactor),
+            _lock: self._lock.clone(),
+        }
+    }
+}
+
+/// Runs compactions on a background thread.
+///
+/// The thread only holds the `Core`, so dropping the last `KvStore` handle waits
+/// for a running compaction instead of leaving it behind.
+#[derive(Default)]
+struct Compactor {
+    running: Arc<AtomicBool>,
+    handle: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Compactor {
+    /// Start compacting `core` unless a background compaction is already running.
+    fn spawn(&self, core: &Core) {
+        if self.running.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let mut handle = self.handle.lock().unwrap();
+        if let Some(finished) = handle.take() {
+            let _ = finished.join();
+        }
+        let core = core.clone();
+        let running = Arc::clone(&self.running);
+        *handle = Some(thread::spawn(move || {
+            if let Err(e) = core.compact() {
+                log::error!("Compaction failed: {:?}", e);
+            }
+            running.store(false, Ordering::SeqCst);
+        }));
+    }
+}
+
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.get_mut().unwrap().take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Exclusive advisory lock on a store directory, released when dropped.
+struct DirLock(File);
+
+impl DirLock {
+    /// Lock the directory at `path`, failing with `ErrorKind::Locked` if another
+    /// store holds it.
+    fn acquire(path: &Path) -> Result<DirLock> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path.join(LOCK_FILE))?;
+        match FileExt::try_lock_exclusive(&file) {
+            Ok(()) => Ok(DirLock(file)),
+            Err(e) if e.raw_os_error() == fs2::lock_contended_error().raw_os_error() => {
+                Err(ErrorKind::Locked(path.to_owned()))
+            }
+            Err(e) => Err(e.into()),
         }
     }
 }
 
+impl Drop for DirLock {
+    fn drop(&mut self) {
+        let _ = FileExt::unlock(&self.0);
+    }
+}
+
 fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
     let mut list: Vec<u64> = fs::read_dir(path)?
         .flat_map(|res| -> Result<_> { Ok(res?.path()) })
@@ -233,26 +1001,183 @@ fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
     Ok(list)
 }
 
+/// Remove compacted logs and hints that were not finished before a crash.
+fn remove_unfinished_compactions(path: &Path) -> Result<()> {
+    for entry in fs::read_dir(path)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension() == Some("compact".as_ref()) {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
 fn new_log_file(
     path: &Path,
     fname: u64,
     readers: &mut HashMap<u64, BufReaderWithPos<File>>,
-) -> Result<BufWriterWithPos<File>> {
+    options: &KvStoreOptions,
+) -> Result<BufWriterWithPos<FaultFile>> {
     let file_name = path.join(format!("{}.log", fname));
     let f = OpenOptions::new()
         .create(true)
-        .write(true)
         .append(true)
         .open(&file_name)?;
-    let writer = BufWriterWithPos::new(f);
-    readers.insert(fname, BufReaderWithPos::new(File::open(file_name)?));
+    let mut writer = BufWriterWithPos::with_capacity(
+        options.write_buffer_size,
+        FaultFile::new(f, options.faults.clone()),
+    );
+    record::write_file_header(&mut writer)?;
+    writer.flush()?;
+    if options.sync != SyncPolicy::Never {
+        // synced writes to the file are lost if its directory entry is not
+        sync_dir(path)?;
+    }
+    readers.insert(
+        fname,
+        BufReaderWithPos::with_capacity(options.read_buffer_size, File::open(file_name)?),
+    );
     Ok(writer)
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-enum Command {
-    Set { key: String, val: String },
-    Remove { key: String },
+/// Make the creation, renaming and removal of files in `path` durable.
+fn sync_dir(path: &Path) -> io::Result<()> {
+    // directories can't be opened for syncing on Windows
+    if cfg!(unix) {
+        File::open(path)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Rewrite the live entries of logs in an older format into a single log of the
+/// current format.
+///
+/// Both legacy JSON logs and binary logs of an older version are migrated. The new
+/// log is written after all existing generations and synced before the old files
+/// are removed, so a crash in between only repeats the migration.
+/// Return the generation list after migration.
+fn migrate_legacy_logs(path: &Path, list: Vec<u64>, options: &KvStoreOptions) -> Result<Vec<u64>> {
+    let mut legacy = Vec::new();
+    for &fname in &list {
+        let mut f = File::open(path.join(format!("{}.log", fname)))?;
+        match record::read_file_header(&mut f)? {
+            Some(VERSION) => {}
+            Some(version) if version > VERSION => {
+                return Err(ErrorKind::Other(format!(
+                    "unsupported format of {}.log",
+                    fname
+                )))
+            }
+            version => legacy.push((fname, version)),
+        }
+    }
+    if legacy.is_empty() {
+        return Ok(list);
+    }
+
+    let mut live = HashMap::new();
+    let mut apply = |cmd| {
+        let cmds = match cmd {
+            Command::Batch(cmds) => cmds,
+            cmd => vec![cmd],
+        };
+        for cmd in cmds {
+            match cmd {
+                Command::Set {
+                    key,
+                    val,
+                    expires_at,
+                } => live.insert(key, (val, expires_at)),
+                Command::Remove { key } => live.remove(&key),
+                Command::Batch(_) => unreachable!("batches are never nested"),
+            };
+        }
+    };
+    for &(fname, version) in &legacy {
+        let mut reader = BufReader::new(File::open(path.join(format!("{}.log", fname)))?);
+        match version {
+            None => Deserializer::from_reader(reader)
+                .into_iter::<LegacyCommand>()
+                .map_while(std::result::Result::ok)
+                .for_each(|cmd| {
+                    apply(cmd.into());
+                }),
+            Some(version) => {
+                reader.seek(SeekFrom::Start(FILE_HEADER_LEN))?;
+                while let Ok(Some((cmd, _))) = Command::read_from(&mut reader, version) {
+                    apply(cmd);
+                }
+            }
+        }
+    }
+
+    let fname = list.last().unwrap_or(&0) + 1;
+    let mut writer = new_log_file(path, fname, &mut HashMap::new(), options)?;
+    let now = now_millis();
+    for (key, (val, expires_at)) in live {
+        if expires_at.is_some_and(|expires_at| expires_at <= now) {
+            continue;
+        }
+        let cmd = Command::Set {
+            key,
+            val,
+            expires_at,
+        };
+        writer.write_all(&cmd.encode())?;
+    }
+    writer.flush()?;
+    writer.writer.get_ref().sync_all()?;
+    sync_dir(path)?;
+
+    for (fname, _) in &legacy {
+        fs::remove_file(path.join(format!("{}.log", fname)))?;
+        let hint = path.join(format!("{}.hint", fname));
+        if hint.exists() {
+            fs::remove_file(hint)?;
+        }
+    }
+    Ok(list
+        .into_iter()
+        .filter(|fname| !legacy.iter().any(|(f, _)| f == fname))
+        .chain(Some(fname))
+        .collect())
+}
+
+/// Whether a replay error means the record itself is damaged or cut off.
+fn is_damaged(err: &ErrorKind) -> bool {
+    match err {
+        ErrorKind::ReadFail => true,
+        ErrorKind::Io(e) => e.kind() == io::ErrorKind::UnexpectedEof,
+        _ => false,
+    }
+}
+
+/// A command waiting in the group commit queue.
+struct PendingWrite {
+    cmd: Command,
+    // value the key must have for a compare-and-swap
+    expected: Option<Option<Vec<u8>>>,
+    done: Mutex<Option<Result<()>>>,
+}
+
+/// A version of a key: the record that set or removed it.
+#[derive(Debug, Clone)]
+struct Version {
+    // sequence number of the write, 0 for writes replayed on open
+    seq: u64,
+    cmd: CommandPointer,
+    removed: bool,
+}
+
+impl Version {
+    fn is_live(&self, now: u64) -> bool {
+        !self.removed && !self.cmd.is_expired(now)
+    }
+
+    /// Whether this version is the record at `cmd`.
+    fn is_at(&self, cmd: &CommandPointer) -> bool {
+        self.cmd.fname == cmd.fname && self.cmd.pos == cmd.pos
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -260,14 +1185,22 @@ struct CommandPointer {
     fname: u64,
     pos: u64,
     len: u64,
+    expires_at: Option<u64>,
+}
+
+impl CommandPointer {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
 }
 
 impl From<(u64, Range<u64>)> for CommandPointer {
     fn from((fname, range): (u64, Range<u64>)) -> CommandPointer {
         CommandPointer {
-            fname: fname,
+            fname,
             pos: range.start,
             len: range.end - range.start,
+            expires_at: None,
         }
     }
 }
@@ -287,9 +1220,9 @@ struct BufReaderWithPos<R: Read + Seek> {
 //}
 
 impl<R: Read + Seek> BufReaderWithPos<R> {
-    fn new(inner: R) -> BufReaderWithPos<R> {
+    fn with_capacity(capacity: usize, inner: R) -> BufReaderWithPos<R> {
         BufReaderWithPos {
-            reader: BufReader::new(inner),
+            reader: BufReader::with_capacity(capacity, inner),
             pos: 0,
         }
     }
@@ -328,14 +1261,29 @@ struct BufWriterWithPos<W: Write + Seek> {
 //}
 
 impl<W: Write + Seek> BufWriterWithPos<W> {
-    fn new(inner: W) -> BufWriterWithPos<W> {
+    fn with_capacity(capacity: usize, inner: W) -> BufWriterWithPos<W> {
         BufWriterWithPos {
-            writer: BufWriter::new(inner),
+            writer: BufWriter::with_capacity(capacity, inner),
             pos: 0,
         }
     }
 }
 
+impl BufWriterWithPos<FaultFile> {
+    /// Drop everything written after `pos`, whether it reached the file or is
+    /// still buffered.
+    fn truncate(self, pos: u64) -> io::Result<BufWriterWithPos<FaultFile>> {
+        let capacity = self.writer.capacity();
+        // taken apart rather than dropped, which would flush the buffer
+        let (file, _) = self.writer.into_parts();
+        file.set_len(pos)?;
+        Ok(BufWriterWithPos {
+            writer: BufWriter::with_capacity(capacity, file),
+            pos,
+        })
+    }
+}
+
 impl<W> Write for BufWriterWithPos<W>
 where
     W: Write + Seek,
@@ -0,0 +1,169 @@
+//! Fault injection into the file writes of a `KvStore`, for crash tests.
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+
+/// Points of `KvStore::compact` at which a crash can be injected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CrashPoint {
+    /// The compacted log is written and synced under its temporary name.
+    CompactedLogWritten,
+    /// The compacted log is renamed into place, its hints are not written yet.
+    CompactedLogRenamed,
+    /// The hints are written, no stale log is removed yet.
+    HintsWritten,
+    /// The first stale log is removed, its hints and the other stale logs are not.
+    StaleLogRemoved,
+}
+
+/// Makes the file writes of a `KvStore` fail at a chosen point, as if the process
+/// died there.
+///
+/// Once a crash fires, every later write fails too, so nothing more reaches the
+/// disk. Dropping the store and opening it again without faults then shows what
+/// a restart after the crash would find. A write failure injected with
+/// `fail_once_after_bytes` only fails a single write instead, as a full disk
+/// might.
+///
+/// Passed to the store with `KvStoreOptions::fault_injector`.
+#[derive(Debug, Default)]
+pub struct FaultInjector {
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    // bytes of log writes left before the crash
+    write_budget: Option<u64>,
+    // whether running out of the budget only fails that write
+    fail_once: bool,
+    crash_point: Option<CrashPoint>,
+    crashed: bool,
+}
+
+impl FaultInjector {
+    /// Create an injector with no fault armed.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Crash once `bytes` more bytes are written to log files. The write crossing
+    /// the limit is truncated there, leaving a torn record behind.
+    pub fn fail_after_bytes(&self, bytes: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.write_budget = Some(bytes);
+        state.fail_once = false;
+    }
+
+    /// Fail the write crossing the limit of `bytes` more bytes to log files like
+    /// `fail_after_bytes`, but let every later write succeed.
+    pub fn fail_once_after_bytes(&self, bytes: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.write_budget = Some(bytes);
+        state.fail_once = true;
+    }
+
+    /// Crash when compaction reaches `point`.
+    pub fn crash_at(&self, point: CrashPoint) {
+        self.state.lock().unwrap().crash_point = Some(point);
+    }
+
+    /// Whether a crash has fired.
+    pub fn has_crashed(&self) -> bool {
+        self.state.lock().unwrap().crashed
+    }
+
+    /// Fail if the armed crash point is `point` or a fault fired before.
+    pub(super) fn reach(&self, point: CrashPoint) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.crashed || state.crash_point == Some(point) {
+            return Err(state.crash());
+        }
+        Ok(())
+    }
+
+    /// Fail if a crash fired before.
+    fn check(&self) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.crashed {
+            return Err(state.crash());
+        }
+        Ok(())
+    }
+
+    fn write(&self, file: &mut File, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        if state.crashed {
+            return Err(state.crash());
+        }
+        match state.write_budget {
+            Some(budget) if budget < buf.len() as u64 => {
+                file.write_all(&buf[..budget as usize])?;
+                if state.fail_once {
+                    state.write_budget = None;
+                    return Err(io::Error::other("injected write failure"));
+                }
+                return Err(state.crash());
+            }
+            Some(budget) => state.write_budget = Some(budget - buf.len() as u64),
+            None => {}
+        }
+        file.write_all(buf)?;
+        Ok(buf.len())
+    }
+}
+
+impl State {
+    fn crash(&mut self) -> io::Error {
+        self.crashed = true;
+        io::Error::other("injected crash")
+    }
+}
+
+/// A log file whose writes go through an optional `FaultInjector`.
+pub(super) struct FaultFile {
+    file: File,
+    faults: Option<Arc<FaultInjector>>,
+}
+
+impl FaultFile {
+    pub(super) fn new(file: File, faults: Option<Arc<FaultInjector>>) -> FaultFile {
+        FaultFile { file, faults }
+    }
+
+    /// Truncate or extend the file, unless a crash fired before.
+    pub(super) fn set_len(&self, len: u64) -> io::Result<()> {
+        if let Some(faults) = &self.faults {
+            faults.check()?;
+        }
+        self.file.set_len(len)
+    }
+}
+
+impl Deref for FaultFile {
+    type Target = File;
+
+    fn deref(&self) -> &File {
+        &self.file
+    }
+}
+
+impl Write for FaultFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &self.faults {
+            Some(faults) => faults.write(&mut self.file, buf),
+            None => self.file.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for FaultFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
@@ -0,0 +1,111 @@
+//! Hint files of compacted `KvStore` logs.
+//!
+//! A hint file `<n>.hint` lists where every record of the compacted log `<n>.log`
+//! lives, so opening the store fills the index without reading any value. It
+//! starts with the magic bytes `KVH\0` and a `u32` version, followed by entries
+//!
+//! ```text
+//! | crc: u32 | key_len: u32 | pos: u64 | len: u64 | expires_at: u64 | key |
+//! ```
+//!
+//! with all integers little-endian. `crc` is the CRC32 of everything after it.
+//! `expires_at` is 0 for keys without a TTL. Version 1 hints had no `expires_at`
+//! and are ignored.
+use crate::Result;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+const MAGIC: [u8; 4] = *b"KVH\0";
+const VERSION: u32 = 2;
+const ENTRY_HEADER_LEN: usize = 28;
+
+/// Location of one live record in a compacted log.
+pub struct Hint {
+    pub key: Vec<u8>,
+    pub pos: u64,
+    pub len: u64,
+    pub expires_at: Option<u64>,
+}
+
+/// Write the hints of a compacted log to `path`.
+///
+/// The file is written under a temporary name, synced and renamed, so a hint
+/// file is either complete or missing, and the rename is synced too.
+pub fn write_hints<'a>(
+    path: &Path,
+    hints: impl Iterator<Item = (&'a [u8], u64, u64, Option<u64>)>,
+) -> Result<()> {
+    let tmp_path = path.with_extension("hint.compact");
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&VERSION.to_le_bytes())?;
+    for (key, pos, len, expires_at) in hints {
+        let mut entry = Vec::with_capacity(ENTRY_HEADER_LEN + key.len());
+        entry.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        entry.extend_from_slice(&pos.to_le_bytes());
+        entry.extend_from_slice(&len.to_le_bytes());
+        entry.extend_from_slice(&expires_at.unwrap_or(0).to_le_bytes());
+        entry.extend_from_slice(key);
+        writer.write_all(&crc32fast::hash(&entry).to_le_bytes())?;
+        writer.write_all(&entry)?;
+    }
+    writer.flush()?;
+    writer.get_ref().sync_data()?;
+    fs::rename(tmp_path, path)?;
+    super::sync_dir(path.parent().unwrap_or(Path::new(".")))?;
+    Ok(())
+}
+
+/// Read the hints stored at `path`.
+///
+/// Return `None` if there is no hint file or it is damaged, in which case the
+/// log has to be replayed instead.
+pub fn read_hints(path: &Path) -> Result<Option<Vec<Hint>>> {
+    let f = match File::open(path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut reader = BufReader::new(f);
+    let mut header = [0; 8];
+    if reader.read_exact(&mut header).is_err()
+        || header[..4] != MAGIC
+        || header[4..] != VERSION.to_le_bytes()
+    {
+        return Ok(None);
+    }
+
+    let mut hints = Vec::new();
+    loop {
+        let mut entry = [0; 4 + ENTRY_HEADER_LEN];
+        match reader.read(&mut entry[..1])? {
+            0 => return Ok(Some(hints)),
+            _ => {
+                if reader.read_exact(&mut entry[1..]).is_err() {
+                    return Ok(None);
+                }
+            }
+        }
+        let crc = u32::from_le_bytes(entry[..4].try_into().unwrap());
+        let key_len = u32::from_le_bytes(entry[4..8].try_into().unwrap()) as u64;
+        let pos = u64::from_le_bytes(entry[8..16].try_into().unwrap());
+        let len = u64::from_le_bytes(entry[16..24].try_into().unwrap());
+        let expires_at = u64::from_le_bytes(entry[24..32].try_into().unwrap());
+
+        let mut key = Vec::new();
+        reader.by_ref().take(key_len).read_to_end(&mut key)?;
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&entry[4..]);
+        hasher.update(&key);
+        if (key.len() as u64) < key_len || hasher.finalize() != crc {
+            return Ok(None);
+        }
+        hints.push(Hint {
+            key,
+            pos,
+            len,
+            expires_at: Some(expires_at).filter(|&t| t != 0),
+        });
+    }
+}
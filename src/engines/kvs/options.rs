@@ -0,0 +1,168 @@
+//! Options for opening a `KvStore`.
+use super::{FaultInjector, KvStore, SyncPolicy, COMPACTION_THRESHOLD};
+use crate::{ErrorKind, Result};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+const DEFAULT_BUFFER_SIZE: usize = 8 * 1024;
+
+/// When `KvStore` starts a background compaction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompactionPolicy {
+    /// Compact once this many bytes of the logs are stale.
+    StaleBytes(u64),
+    /// Compact once the stale part of the logs reaches `ratio` (between 0 and 1),
+    /// but never for less than `min_stale_bytes` of stale data.
+    StaleRatio {
+        /// fraction of stale bytes
+        ratio: f64,
+        /// lower bound of stale bytes
+        min_stale_bytes: u64,
+    },
+    /// Compact once there are more than this many log files.
+    MaxLogFiles(usize),
+    /// Only compact when `KvStore::compact` is called.
+    Manual,
+}
+
+impl Default for CompactionPolicy {
+    fn default() -> Self {
+        CompactionPolicy::StaleBytes(COMPACTION_THRESHOLD)
+    }
+}
+
+/// Builder of the options used to open a `KvStore`.
+///
+/// ```rust
+/// # use kvs::{CompactionPolicy, KvStoreOptions, SyncPolicy};
+/// # use tempfile::TempDir;
+/// let temp_dir = TempDir::new().unwrap();
+/// let store = KvStoreOptions::new()
+///     .sync(SyncPolicy::EveryWrite)
+///     .compaction(CompactionPolicy::MaxLogFiles(8))
+///     .create_if_missing(false)
+///     .open(temp_dir.path())
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    pub(super) sync: SyncPolicy,
+    pub(super) compaction: CompactionPolicy,
+    pub(super) create_if_missing: bool,
+    pub(super) read_only: bool,
+    pub(super) write_buffer_size: usize,
+    pub(super) read_buffer_size: usize,
+    pub(super) faults: Option<Arc<FaultInjector>>,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        Self {
+            sync: SyncPolicy::default(),
+            compaction: CompactionPolicy::default(),
+            create_if_missing: true,
+            read_only: false,
+            write_buffer_size: DEFAULT_BUFFER_SIZE,
+            read_buffer_size: DEFAULT_BUFFER_SIZE,
+            faults: None,
+        }
+    }
+}
+
+impl KvStoreOptions {
+    /// Default options.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set when writes are synced to disk.
+    pub fn sync(mut self, policy: SyncPolicy) -> Self {
+        self.sync = policy;
+        self
+    }
+
+    /// Set when background compactions start.
+    pub fn compaction(mut self, policy: CompactionPolicy) -> Self {
+        self.compaction = policy;
+        self
+    }
+
+    /// Set whether a missing store directory is created. Enabled by default.
+    pub fn create_if_missing(mut self, create: bool) -> Self {
+        self.create_if_missing = create;
+        self
+    }
+
+    /// Set whether the store is opened for reading only, as described at
+    /// `KvStore::open_read_only`. Needs `create_if_missing(false)`.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Set the buffer size of the log writer in bytes.
+    pub fn write_buffer_size(mut self, size: usize) -> Self {
+        self.write_buffer_size = size;
+        self
+    }
+
+    /// Set the buffer size of each log reader in bytes.
+    pub fn read_buffer_size(mut self, size: usize) -> Self {
+        self.read_buffer_size = size;
+        self
+    }
+
+    /// Inject faults into the file writes of the store, to test crash recovery.
+    pub fn fault_injector(mut self, faults: Arc<FaultInjector>) -> Self {
+        self.faults = Some(faults);
+        self
+    }
+
+    /// Open the KvStore at a given path with these options.
+    ///
+    /// # Errors
+    ///
+    /// Return `ErrorKind::InvalidOption` if an option is out of range, and
+    /// `ErrorKind::NotFound` if the directory is missing and may not be created.
+    pub fn open(&self, path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with_options(path.into(), self)
+    }
+
+    pub(super) fn validate(&self) -> Result<()> {
+        let invalid = |name, reason: &str| {
+            Err(ErrorKind::InvalidOption {
+                name,
+                reason: reason.to_owned(),
+            })
+        };
+        match self.sync {
+            SyncPolicy::EveryN(0) => return invalid("sync", "EveryN needs at least 1 write"),
+            SyncPolicy::Interval(0) => return invalid("sync", "Interval needs at least 1 ms"),
+            _ => {}
+        }
+        match self.compaction {
+            CompactionPolicy::StaleBytes(0) => {
+                return invalid("compaction", "StaleBytes needs at least 1 byte")
+            }
+            // also rejects NaN
+            CompactionPolicy::StaleRatio { ratio, .. } if !(ratio > 0.0 && ratio <= 1.0) => {
+                return invalid("compaction", "StaleRatio needs a ratio in (0, 1]")
+            }
+            // a compaction itself leaves two log files behind
+            CompactionPolicy::MaxLogFiles(max) if max < 2 => {
+                return invalid("compaction", "MaxLogFiles needs at least 2 files")
+            }
+            _ => {}
+        }
+        if self.read_only && self.create_if_missing {
+            return invalid("read_only", "can't create a missing store");
+        }
+        if self.write_buffer_size == 0 {
+            return invalid("write_buffer_size", "must not be 0");
+        }
+        if self.read_buffer_size == 0 {
+            return invalid("read_buffer_size", "must not be 0");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,267 @@
+//! Binary format of `KvStore` log files.
+//!
+//! Every log file starts with an 8-byte header: the magic bytes `KVS\0` and a
+//! little-endian `u32` format version. It is followed by records laid out as
+//!
+//! ```text
+//! | crc: u32 | header_crc: u32 | tag: u8 | key_len: u32 | val_len: u32 | key | val |
+//! ```
+//!
+//! with all integers little-endian. `crc` is the CRC32 of everything after it,
+//! and `header_crc` the CRC32 of `tag`, `key_len` and `val_len`, so a damaged
+//! length is caught before it is used. `Remove` records have an empty value.
+//! Version 1 records have no `crc` and versions before 6 no `header_crc`. Keys
+//! and values are arbitrary bytes since version 5, and valid UTF-8 before.
+//!
+//! A `Set` record of a key with a TTL has its own tag, and its value starts with
+//! the `u64` expiry time in milliseconds since the UNIX epoch. These records were
+//! added in version 4.
+//!
+//! A `Batch` record has an empty key and its value is the records of its commands
+//! back-to-back. Its `crc` covers all of them, so a batch is replayed either
+//! completely or not at all. Batches were added in version 3.
+use crate::{ErrorKind, Result};
+use serde::Deserialize;
+use std::io::{self, Read, Write};
+
+/// Magic bytes at the start of every binary log file.
+pub const MAGIC: [u8; 4] = *b"KVS\0";
+/// Current version of the log format.
+pub const VERSION: u32 = 6;
+/// Length of the file header.
+pub const FILE_HEADER_LEN: u64 = 8;
+
+const CRC_LEN: usize = 4;
+const RECORD_HEADER_LEN: usize = 9;
+/// Offset of the first command in a batch record.
+pub const BATCH_HEADER_LEN: u64 = (2 * CRC_LEN + RECORD_HEADER_LEN) as u64;
+const TAG_SET: u8 = 1;
+const TAG_REMOVE: u8 = 2;
+const TAG_BATCH: u8 = 3;
+const TAG_SET_EXPIRING: u8 = 4;
+const EXPIRY_LEN: usize = 8;
+
+/// A single entry of the log.
+#[derive(Debug)]
+pub enum Command {
+    Set {
+        key: Vec<u8>,
+        val: Vec<u8>,
+        /// milliseconds since the UNIX epoch
+        expires_at: Option<u64>,
+    },
+    Remove {
+        key: Vec<u8>,
+    },
+    /// Commands applied atomically, never nested.
+    Batch(Vec<Command>),
+}
+
+/// A single entry of a legacy JSON log, which only held strings.
+#[derive(Deserialize, Debug)]
+pub enum LegacyCommand {
+    Set { key: String, val: String },
+    Remove { key: String },
+}
+
+impl From<LegacyCommand> for Command {
+    fn from(cmd: LegacyCommand) -> Command {
+        match cmd {
+            LegacyCommand::Set { key, val } => Command::Set {
+                key: key.into_bytes(),
+                val: val.into_bytes(),
+                expires_at: None,
+            },
+            LegacyCommand::Remove { key } => Command::Remove {
+                key: key.into_bytes(),
+            },
+        }
+    }
+}
+
+impl Command {
+    /// Encode the command as one binary record of the current version.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.encoded_len() as usize);
+        buf.extend_from_slice(&[0; 2 * CRC_LEN]);
+        match self {
+            Command::Set {
+                key,
+                val,
+                expires_at: None,
+            } => {
+                buf.push(TAG_SET);
+                buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+                buf.extend_from_slice(&(val.len() as u32).to_le_bytes());
+                buf.extend_from_slice(key);
+                buf.extend_from_slice(val);
+            }
+            Command::Set {
+                key,
+                val,
+                expires_at: Some(expires_at),
+            } => {
+                buf.push(TAG_SET_EXPIRING);
+                buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+                buf.extend_from_slice(&((EXPIRY_LEN + val.len()) as u32).to_le_bytes());
+                buf.extend_from_slice(key);
+                buf.extend_from_slice(&expires_at.to_le_bytes());
+                buf.extend_from_slice(val);
+            }
+            Command::Remove { key } => {
+                buf.push(TAG_REMOVE);
+                buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+                buf.extend_from_slice(&0u32.to_le_bytes());
+                buf.extend_from_slice(key);
+            }
+            Command::Batch(cmds) => {
+                let body_len = self.encoded_len() - BATCH_HEADER_LEN;
+                buf.push(TAG_BATCH);
+                buf.extend_from_slice(&0u32.to_le_bytes());
+                buf.extend_from_slice(&(body_len as u32).to_le_bytes());
+                for cmd in cmds {
+                    buf.extend_from_slice(&cmd.encode());
+                }
+            }
+        }
+        let header_crc = crc32fast::hash(&buf[2 * CRC_LEN..BATCH_HEADER_LEN as usize]);
+        buf[CRC_LEN..2 * CRC_LEN].copy_from_slice(&header_crc.to_le_bytes());
+        let crc = crc32fast::hash(&buf[CRC_LEN..]);
+        buf[..CRC_LEN].copy_from_slice(&crc.to_le_bytes());
+        buf
+    }
+
+    /// Length of the record `encode` produces.
+    pub fn encoded_len(&self) -> u64 {
+        BATCH_HEADER_LEN
+            + match self {
+                Command::Set {
+                    key,
+                    val,
+                    expires_at,
+                } => {
+                    let expiry_len = if expires_at.is_some() { EXPIRY_LEN } else { 0 };
+                    (key.len() + expiry_len + val.len()) as u64
+                }
+                Command::Remove { key } => key.len() as u64,
+                Command::Batch(cmds) => cmds.iter().map(Command::encoded_len).sum(),
+            }
+    }
+
+    /// Decode a record of the current version previously produced by `encode`.
+    ///
+    /// # Errors
+    ///
+    /// Return `ErrorKind::ReadFail` if the record is damaged.
+    pub fn decode(buf: &[u8]) -> Result<Command> {
+        Command::read_from(&mut &buf[..], VERSION)?
+            .map(|(cmd, _)| cmd)
+            .ok_or(ErrorKind::ReadFail)
+    }
+
+    /// Read the next record written in format `version` and its length in bytes.
+    ///
+    /// Return `None` if the reader is at the end of the log.
+    ///
+    /// # Errors
+    ///
+    /// A record cut off in the middle is reported as `io::ErrorKind::UnexpectedEof`,
+    /// and a record failing either checksum as `ErrorKind::ReadFail`. Since
+    /// version 6 the header is checked first, so a damaged length is never
+    /// mistaken for a record cut off.
+    pub fn read_from<R: Read>(reader: &mut R, version: u32) -> Result<Option<(Command, u64)>> {
+        let crc_len = if version >= 2 { CRC_LEN } else { 0 };
+        let header_crc_len = if version >= 6 { CRC_LEN } else { 0 };
+        let mut header = [0; 2 * CRC_LEN + RECORD_HEADER_LEN];
+        let header = &mut header[2 * CRC_LEN - crc_len - header_crc_len..];
+        match reader.read(&mut header[..1])? {
+            0 => return Ok(None),
+            _ => reader.read_exact(&mut header[1..])?,
+        }
+        let (crc, header) = header.split_at(crc_len);
+        let (header_crc, header) = header.split_at(header_crc_len);
+        if version >= 6 && crc32fast::hash(header).to_le_bytes() != header_crc {
+            return Err(ErrorKind::ReadFail);
+        }
+        let key_len = u32::from_le_bytes(header[1..5].try_into().unwrap()) as u64;
+        let val_len = u32::from_le_bytes(header[5..9].try_into().unwrap()) as u64;
+
+        // grow the buffer while reading so a damaged length can't allocate gigabytes
+        let mut body = Vec::new();
+        reader
+            .by_ref()
+            .take(key_len + val_len)
+            .read_to_end(&mut body)?;
+        if (body.len() as u64) < key_len + val_len {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        if version >= 2 {
+            let mut hasher = crc32fast::Hasher::new();
+            hasher.update(header_crc);
+            hasher.update(header);
+            hasher.update(&body);
+            if hasher.finalize().to_le_bytes() != crc {
+                return Err(ErrorKind::ReadFail);
+            }
+        }
+
+        let len = (crc_len + header_crc_len + RECORD_HEADER_LEN) as u64 + key_len + val_len;
+        if header[0] == TAG_BATCH && key_len == 0 && version >= 3 {
+            let mut cmds = Vec::new();
+            let mut reader = &body[..];
+            while let Some((cmd, _)) =
+                Command::read_from(&mut reader, version).map_err(|_| ErrorKind::ReadFail)?
+            {
+                if let Command::Batch(_) = cmd {
+                    return Err(ErrorKind::ReadFail);
+                }
+                cmds.push(cmd);
+            }
+            return Ok(Some((Command::Batch(cmds), len)));
+        }
+
+        let mut val = body.split_off(key_len as usize);
+        let key = body;
+        let cmd = match header[0] {
+            TAG_SET => Command::Set {
+                key,
+                val,
+                expires_at: None,
+            },
+            TAG_SET_EXPIRING if val.len() >= EXPIRY_LEN && version >= 4 => {
+                let expires_at = u64::from_le_bytes(val[..EXPIRY_LEN].try_into().unwrap());
+                val.drain(..EXPIRY_LEN);
+                Command::Set {
+                    key,
+                    val,
+                    expires_at: Some(expires_at),
+                }
+            }
+            TAG_REMOVE => Command::Remove { key },
+            _ => return Err(ErrorKind::ReadFail),
+        };
+        Ok(Some((cmd, len)))
+    }
+}
+
+/// Write the header of a new log file.
+pub fn write_file_header<W: Write>(writer: &mut W) -> io::Result<()> {
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&VERSION.to_le_bytes())
+}
+
+/// Read the header of a log file and return its format version.
+///
+/// Return `None` if the file does not start with a binary header, i.e. it is a
+/// legacy JSON log.
+pub fn read_file_header<R: Read>(reader: &mut R) -> Result<Option<u32>> {
+    let mut header = [0; FILE_HEADER_LEN as usize];
+    match reader.read_exact(&mut header) {
+        Ok(()) if header[..4] == MAGIC => {
+            Ok(Some(u32::from_le_bytes(header[4..].try_into().unwrap())))
+        }
+        Ok(()) => Ok(None),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
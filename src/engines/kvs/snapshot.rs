@@ -0,0 +1,88 @@
+//! Point-in-time views of a `KvStore`.
+use super::Core;
+use crate::engines::prefix_range;
+use crate::{Result, ScanIter};
+use std::ops::Bound;
+use std::sync::atomic::Ordering;
+
+/// A read-only view of a `KvStore` as of the moment it was taken.
+///
+/// Created by `KvStore::snapshot`. Writes made after that are not visible through
+/// the snapshot, and the store keeps the versions it sees until it is dropped, so
+/// long-lived snapshots hold back compaction.
+pub struct Snapshot {
+    core: Core,
+    seq: u64,
+}
+
+impl Snapshot {
+    pub(super) fn new(core: &Core) -> Snapshot {
+        // writes are applied with the lock held, so `seq` is never halfway a group
+        let mut snapshots = core.snapshots.lock().unwrap();
+        let seq = core.seq.load(Ordering::SeqCst);
+        *snapshots.entry(seq).or_insert(0) += 1;
+        Snapshot {
+            core: core.clone(),
+            seq,
+        }
+    }
+
+    /// Sequence number of the last write the snapshot sees.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// Get the value of a key as of the snapshot.
+    ///
+    /// If the key does not exist, return None.
+    pub fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.core.get_at(&key, Some(self.seq))
+    }
+
+    /// Get the string value of a string key as of the snapshot.
+    ///
+    /// If the key does not exist, return None.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self
+            .get_bytes(key.into_bytes())?
+            .map(String::from_utf8)
+            .transpose()?)
+    }
+
+    /// Iterate over the key/value pairs whose keys fall in `range` as of the
+    /// snapshot, in key order.
+    pub fn scan(&self, range: (Bound<String>, Bound<String>)) -> Result<ScanIter> {
+        Ok(self.core.scan(range, Some(self.seq)))
+    }
+
+    /// Iterate over the key/value pairs whose keys start with `prefix` as of the
+    /// snapshot, in key order.
+    pub fn scan_prefix(&self, prefix: String) -> Result<ScanIter> {
+        self.scan(prefix_range(&prefix))
+    }
+}
+
+impl Clone for Snapshot {
+    fn clone(&self) -> Self {
+        let mut snapshots = self.core.snapshots.lock().unwrap();
+        *snapshots.entry(self.seq).or_insert(0) += 1;
+        Snapshot {
+            core: self.core.clone(),
+            seq: self.seq,
+        }
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        // the versions only this snapshot saw are dropped by the next write of
+        // their key or the next compaction
+        let mut snapshots = self.core.snapshots.lock().unwrap();
+        if let Some(count) = snapshots.get_mut(&self.seq) {
+            *count -= 1;
+            if *count == 0 {
+                snapshots.remove(&self.seq);
+            }
+        }
+    }
+}
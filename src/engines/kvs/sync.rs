@@ -0,0 +1,108 @@
+//! Durability of `KvStore` writes.
+use crate::Result;
+use std::fs::File;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+
+/// When `KvStore` forces written records from the OS page cache to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SyncPolicy {
+    /// Never `fsync`, leave it to the OS. A power loss may lose acknowledged writes.
+    #[default]
+    Never,
+    /// `fsync` before every write is acknowledged.
+    EveryWrite,
+    /// `fsync` once every N writes.
+    EveryN(u64),
+    /// `fsync` in the background every given number of milliseconds.
+    Interval(u64),
+}
+
+/// Applies a `SyncPolicy` to the active log file.
+///
+/// Writes are numbered in the order they reach the OS. A writer that has to wait
+/// for durability syncs everything written so far, so concurrent writers waiting
+/// behind it find their records already durable and share a single `fsync`.
+pub struct Syncer {
+    policy: SyncPolicy,
+    file: Mutex<File>,
+    written: AtomicU64,
+    synced: Mutex<u64>,
+}
+
+impl Syncer {
+    /// Create a syncer for the active log `file`.
+    pub fn new(policy: SyncPolicy, file: File) -> Arc<Syncer> {
+        let syncer = Arc::new(Syncer {
+            policy,
+            file: Mutex::new(file),
+            written: AtomicU64::new(0),
+            synced: Mutex::new(0),
+        });
+        if let SyncPolicy::Interval(ms) = policy {
+            let weak = Arc::downgrade(&syncer);
+            thread::spawn(move || background_sync(weak, Duration::from_millis(ms)));
+        }
+        syncer
+    }
+
+    /// Record that `count` writes reached the OS and return the sequence number
+    /// of the last one.
+    ///
+    /// Must be called while holding the writer lock, after flushing the writer.
+    pub fn written(&self, count: u64) -> u64 {
+        self.written.fetch_add(count, Ordering::SeqCst) + count
+    }
+
+    /// Make writes up to `seq` durable as required by the policy.
+    pub fn commit(&self, seq: u64) -> Result<()> {
+        match self.policy {
+            SyncPolicy::Never | SyncPolicy::Interval(_) => Ok(()),
+            SyncPolicy::EveryWrite => self.sync_to(seq, 0),
+            SyncPolicy::EveryN(n) => self.sync_to(seq, n - 1),
+        }
+    }
+
+    /// Switch to a new active log file.
+    ///
+    /// Must be called while holding the writer lock. The old file is synced first,
+    /// so no write is left behind in a file the syncer no longer sees.
+    pub fn rotate(&self, file: File) -> Result<()> {
+        let mut active = self.file.lock().unwrap();
+        if self.policy != SyncPolicy::Never {
+            active.sync_data()?;
+        }
+        *active = file;
+        Ok(())
+    }
+
+    /// Sync unless at most `lag` writes up to `seq` are not durable yet.
+    fn sync_to(&self, seq: u64, lag: u64) -> Result<()> {
+        let mut synced = self.synced.lock().unwrap();
+        if *synced + lag >= seq {
+            // another writer's fsync already covered enough of the writes
+            return Ok(());
+        }
+        let target = self.written.load(Ordering::SeqCst);
+        self.file.lock().unwrap().sync_data()?;
+        *synced = target;
+        Ok(())
+    }
+}
+
+fn background_sync(syncer: Weak<Syncer>, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+        // stop once the store is dropped
+        let syncer = match syncer.upgrade() {
+            Some(syncer) => syncer,
+            None => return,
+        };
+        let seq = syncer.written.load(Ordering::SeqCst);
+        if let Err(e) = syncer.sync_to(seq, 0) {
+            log::warn!("background sync failed: {:?}", e);
+        }
+    }
+}
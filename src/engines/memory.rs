@@ -0,0 +1,202 @@
+use super::{byte_range, expires_at, now_millis, BatchOp, WriteBatch};
+use crate::{ErrorKind, KvsEngine, Result, ScanIter};
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// An engine keeping all key/value pairs in memory, e.g. for tests or as a cache.
+///
+/// Nothing is written to disk, so the data is gone once the last handle is
+/// dropped. With a size cap, the least recently used keys are evicted to keep the
+/// total size of keys and values under it.
+#[derive(Clone, Default)]
+pub struct MemoryKvsEngine {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    map: BTreeMap<Vec<u8>, Entry>,
+    // keys by the tick of their last use, oldest first
+    lru: BTreeMap<u64, Vec<u8>>,
+    tick: u64,
+    // total bytes of keys and values
+    size: usize,
+    max_bytes: Option<usize>,
+}
+
+struct Entry {
+    val: Vec<u8>,
+    expires_at: Option<u64>,
+    tick: u64,
+}
+
+impl MemoryKvsEngine {
+    /// Create an empty engine without a size cap.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create an empty engine holding at most `max_bytes` of keys and values.
+    ///
+    /// A single pair larger than the cap is evicted right after being set.
+    pub fn with_max_bytes(max_bytes: usize) -> Self {
+        let engine = Self::new();
+        engine.inner.lock().unwrap().max_bytes = Some(max_bytes);
+        engine
+    }
+
+    /// Total bytes of the keys and values held.
+    pub fn size(&self) -> usize {
+        self.inner.lock().unwrap().size
+    }
+}
+
+impl Inner {
+    /// Value of `key` unless missing or expired, marking it as recently used.
+    fn get(&mut self, key: &[u8]) -> Option<&[u8]> {
+        if self.map.get(key)?.is_expired(now_millis()) {
+            self.remove(key);
+            return None;
+        }
+        self.tick += 1;
+        let tick = self.tick;
+        let entry = self.map.get_mut(key)?;
+        self.lru.remove(&entry.tick);
+        self.lru.insert(tick, key.to_vec());
+        entry.tick = tick;
+        Some(&entry.val)
+    }
+
+    fn insert(&mut self, key: Vec<u8>, val: Vec<u8>, expires_at: Option<u64>) {
+        self.remove(&key);
+        self.tick += 1;
+        self.size += key.len() + val.len();
+        self.lru.insert(self.tick, key.clone());
+        let entry = Entry {
+            val,
+            expires_at,
+            tick: self.tick,
+        };
+        self.map.insert(key, entry);
+    }
+
+    /// Remove `key` and return whether it was live.
+    fn remove(&mut self, key: &[u8]) -> bool {
+        match self.map.remove(key) {
+            Some(entry) => {
+                self.lru.remove(&entry.tick);
+                self.size -= key.len() + entry.val.len();
+                !entry.is_expired(now_millis())
+            }
+            None => false,
+        }
+    }
+
+    /// Evict the least recently used keys until the size is under the cap.
+    fn evict(&mut self) {
+        let Some(max_bytes) = self.max_bytes else {
+            return;
+        };
+        while self.size > max_bytes {
+            let Some((_, key)) = self.lru.pop_first() else {
+                break;
+            };
+            let entry = self.map.remove(&key).expect("LRU keys are in the map");
+            self.size -= key.len() + entry.val.len();
+        }
+    }
+}
+
+impl Entry {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+impl KvsEngine for MemoryKvsEngine {
+    fn set_bytes(&self, key: Vec<u8>, val: Vec<u8>) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.insert(key, val, None);
+        inner.evict();
+        Ok(())
+    }
+
+    fn set_with_ttl(&self, key: String, val: String, ttl: Duration) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.insert(key.into_bytes(), val.into_bytes(), Some(expires_at(ttl)));
+        inner.evict();
+        Ok(())
+    }
+
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        Ok(self.inner.lock().unwrap().get(&key).map(<[u8]>::to_vec))
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        if self.inner.lock().unwrap().remove(&key) {
+            Ok(())
+        } else {
+            Err(ErrorKind::KeyNotFound)
+        }
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        for op in batch.ops {
+            match op {
+                BatchOp::Put { key, val } => inner.insert(key.into_bytes(), val.into_bytes(), None),
+                BatchOp::Delete { key } => {
+                    inner.remove(key.as_bytes());
+                }
+            }
+        }
+        inner.evict();
+        Ok(())
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.get(key.as_bytes()) != expected.as_ref().map(String::as_bytes) {
+            return Err(ErrorKind::CompareFailed);
+        }
+        match new {
+            Some(val) => inner.insert(key.into_bytes(), val.into_bytes(), None),
+            None => {
+                inner.remove(key.as_bytes());
+            }
+        }
+        inner.evict();
+        Ok(())
+    }
+
+    fn scan(&self, range: (Bound<String>, Bound<String>)) -> Result<ScanIter> {
+        let Some(range) = byte_range(range) else {
+            return Ok(Box::new(std::iter::empty()));
+        };
+        // scans don't count as use, so they don't flush the cache
+        let now = now_millis();
+        let pairs: Vec<(String, Vec<u8>)> = self
+            .inner
+            .lock()
+            .unwrap()
+            .map
+            .range(range)
+            .filter(|(_, entry)| !entry.is_expired(now))
+            .filter_map(|(key, entry)| {
+                Some((String::from_utf8(key.clone()).ok()?, entry.val.clone()))
+            })
+            .collect();
+        Ok(Box::new(
+            pairs
+                .into_iter()
+                .map(|(key, val)| Ok((key, String::from_utf8(val)?))),
+        ))
+    }
+}
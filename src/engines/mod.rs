@@ -1,28 +1,207 @@
-use crate::Result;
+//! Storage engines and the messages clients send them.
+use crate::{ErrorKind, Result};
 use serde::{Deserialize, Serialize};
+use std::ops::Bound;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Iterator over key/value pairs in key order, returned by a scan.
+pub type ScanIter = Box<dyn Iterator<Item = Result<(String, String)>>>;
 
 /// Trait for different engine.
-pub trait KvsEngine: Send + 'static {
+///
+/// Keys and values are arbitrary bytes. The string methods are a convenience
+/// layer over the byte ones.
+pub trait KvsEngine: Send + Sync + 'static {
+    /// Set the value of a key to a value.
+    ///
+    /// # Errors
+    ///
+    /// Return an error if the value is not written successfully.
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+    /// Get the value of a key.
+    ///
+    /// If the key does not exist, return None.
+    ///
+    /// # Errors
+    ///
+    /// Return an error if the value is not read successfully.
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+    /// Remove a given key.
+    ///
+    /// # Errors
+    ///
+    /// Return an error if the key does not exist or is not removed successfully.
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()>;
     /// Set the value of a string key to a string.
     ///
     /// # Errors
     ///
     /// Return an error if the value is not written successfully.
-    fn set(&self, key: String, value: String) -> Result<()>;
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+    /// Set the value of a string key to a string that expires after `ttl`.
+    ///
+    /// Once expired, the key reads as missing. Setting the key again without a
+    /// TTL makes it permanent.
+    ///
+    /// # Errors
+    ///
+    /// Return an error if the value is not written successfully.
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()>;
     /// Get the string value of a string key.
     ///
     /// If the key does not exist, return None.
     ///
     /// # Errors
     ///
-    /// Return an error if the value is not read successfully.
-    fn get(&self, key: String) -> Result<Option<String>>;
+    /// Return an error if the value is not read successfully, or
+    /// `ErrorKind::String` if it is not valid UTF-8.
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self
+            .get_bytes(key.into_bytes())?
+            .map(String::from_utf8)
+            .transpose()?)
+    }
     /// Remove a given key.
     ///
     /// # Errors
     ///
     /// Return an error if the key does not exist or is not removed successfully.
-    fn remove(&self, key: String) -> Result<()>;
+    fn remove(&self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+    /// Apply all writes of `batch` atomically, in order.
+    ///
+    /// Deleting a key that does not exist is not an error inside a batch.
+    ///
+    /// # Errors
+    ///
+    /// Return an error if the batch is not written successfully, in which case
+    /// none of its writes are applied.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()>;
+    /// Atomically replace the value of `key` with `new` if it currently is
+    /// `expected`. `None` stands for a missing key on both sides, so keys can be
+    /// created and removed this way.
+    ///
+    /// # Errors
+    ///
+    /// Return `ErrorKind::CompareFailed` if the current value is not `expected`.
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<()>;
+    /// Iterate over the key/value pairs whose keys fall in `range`, in key order.
+    ///
+    /// # Errors
+    ///
+    /// Return an error if the scan can't start. Values failing to be read or
+    /// not valid UTF-8 are reported as errors of the iterator. Keys that are not
+    /// valid UTF-8 are skipped.
+    fn scan(&self, range: (Bound<String>, Bound<String>)) -> Result<ScanIter>;
+    /// Iterate over the key/value pairs whose keys start with `prefix`, in key order.
+    ///
+    /// # Errors
+    ///
+    /// Same as `scan`.
+    fn scan_prefix(&self, prefix: String) -> Result<ScanIter> {
+        self.scan(prefix_range(&prefix))
+    }
+}
+
+/// A group of writes applied atomically by `KvsEngine::write_batch`.
+#[derive(Debug, Default, Clone)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+#[derive(Debug, Clone)]
+enum BatchOp {
+    Put { key: String, val: String },
+    Delete { key: String },
+}
+
+impl WriteBatch {
+    /// Create an empty batch.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the value of a string key to a string.
+    pub fn put(&mut self, key: String, val: String) {
+        self.ops.push(BatchOp::Put { key, val });
+    }
+
+    /// Remove a given key.
+    pub fn delete(&mut self, key: String) {
+        self.ops.push(BatchOp::Delete { key });
+    }
+
+    /// Number of writes in the batch.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Whether the batch holds no writes.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+/// Current time in milliseconds since the UNIX epoch.
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock is before the UNIX epoch")
+        .as_millis() as u64
+}
+
+/// Expiry time of a key set now with `ttl`, in milliseconds since the UNIX epoch.
+fn expires_at(ttl: Duration) -> u64 {
+    now_millis().saturating_add(ttl.as_millis().try_into().unwrap_or(u64::MAX))
+}
+
+/// Range of the keys starting with `prefix`, for `KvsEngine::scan`.
+pub fn prefix_range(prefix: &str) -> (Bound<String>, Bound<String>) {
+    // the keys before the first one with a greater last char, skipping chars
+    // without a successor
+    let mut end = prefix.to_owned();
+    let end = loop {
+        let Some(c) = end.pop() else {
+            break Bound::Unbounded;
+        };
+        if let Some(next) = (c as u32 + 1..=char::MAX as u32).find_map(char::from_u32) {
+            end.push(next);
+            break Bound::Excluded(end);
+        }
+    };
+    (Bound::Included(prefix.to_owned()), end)
+}
+
+/// Range of keys as bytes.
+type ByteRange = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+
+/// Byte range of a string scan over `range`, or `None` if it can't hold any
+/// key, which `BTreeMap::range` would panic on.
+///
+/// Keys in the range that aren't strings can't be returned by string scans, so
+/// the engines skip them.
+fn byte_range(range: (Bound<String>, Bound<String>)) -> Option<ByteRange> {
+    let empty = match &range {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start) | Bound::Excluded(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end)) => start >= end,
+        _ => false,
+    };
+    if empty {
+        return None;
+    }
+    Some((
+        range.0.map(String::into_bytes),
+        range.1.map(String::into_bytes),
+    ))
 }
 
 /// Message from client to server.
@@ -40,15 +219,143 @@ pub enum Message {
         /// val
         val: String,
     },
+    /// set with a TTL
+    SetWithTtl {
+        /// key
+        key: String,
+        /// val
+        val: String,
+        /// time to live in milliseconds
+        ttl_ms: u64,
+    },
     /// rm
     Rm {
         /// key
         key: String,
     },
+    /// get a binary key
+    GetBytes {
+        /// key
+        #[serde(with = "crate::protocol::base64_bytes")]
+        key: Vec<u8>,
+    },
+    /// set a binary key
+    SetBytes {
+        /// key
+        #[serde(with = "crate::protocol::base64_bytes")]
+        key: Vec<u8>,
+        /// val
+        #[serde(with = "crate::protocol::base64_bytes")]
+        val: Vec<u8>,
+    },
+    /// rm a binary key
+    RmBytes {
+        /// key
+        #[serde(with = "crate::protocol::base64_bytes")]
+        key: Vec<u8>,
+    },
+    /// compare and swap
+    Cas {
+        /// key
+        key: String,
+        /// value the key must have, `None` if it must not exist
+        expected: Option<String>,
+        /// new value, `None` to remove the key
+        new: Option<String>,
+    },
+    /// scan
+    Scan {
+        /// lower bound of the keys
+        start: Bound<String>,
+        /// upper bound of the keys
+        end: Bound<String>,
+        /// most pairs to return in one page
+        limit: Option<usize>,
+    },
+    /// scan keys with a prefix
+    ScanPrefix {
+        /// prefix
+        prefix: String,
+        /// only return keys after this one, to continue an earlier page
+        after: Option<String>,
+        /// most pairs to return in one page
+        limit: Option<usize>,
+    },
+}
+
+/// Response from server to client.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum Response {
+    /// The request succeeded and carries no value.
+    Ok,
+    /// The value of a `get`, `None` if the key does not exist.
+    Value(Option<String>),
+    /// The value of a `get_bytes`, `None` if the key does not exist.
+    Bytes(#[serde(with = "crate::protocol::base64_bytes::option")] Option<Vec<u8>>),
+    /// One page of the key/value pairs of a scan in key order.
+    Pairs {
+        /// key/value pairs
+        pairs: Vec<(String, String)>,
+        /// last key of the page if more pairs follow it; the next page
+        /// continues after it
+        next: Option<String>,
+    },
+    /// The request failed on the server.
+    Error {
+        /// kind of failure
+        kind: ResponseErrorKind,
+        /// human readable description
+        message: String,
+    },
+}
+
+/// Kind of failure reported in `Response::Error`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseErrorKind {
+    /// Key does not exist
+    KeyNotFound,
+    /// Current value of a compare-and-swap is not the expected one
+    CompareFailed,
+    /// Request could not be decoded
+    Protocol,
+    /// Any other failure inside the engine
+    Internal,
+}
+
+impl From<ErrorKind> for Response {
+    fn from(err: ErrorKind) -> Response {
+        match err {
+            ErrorKind::KeyNotFound => Response::Error {
+                kind: ResponseErrorKind::KeyNotFound,
+                message: "Key not found".to_owned(),
+            },
+            ErrorKind::CompareFailed => Response::Error {
+                kind: ResponseErrorKind::CompareFailed,
+                message: "Value mismatch".to_owned(),
+            },
+            ErrorKind::Protocol(message) => Response::Error {
+                kind: ResponseErrorKind::Protocol,
+                message,
+            },
+            err => Response::Error {
+                kind: ResponseErrorKind::Internal,
+                message: format!("{:?}", err),
+            },
+        }
+    }
 }
 
-pub use self::kvs::KvStore;
+pub use self::kvs::{
+    CompactionPolicy, CompactionStats, CrashPoint, FaultInjector, KvStore, KvStoreOptions,
+    Snapshot, SyncPolicy,
+};
+pub use self::memory::MemoryKvsEngine;
+pub use self::registry::{
+    engine_names, open_engine, register_engine, register_volatile_engine, EngineOpener,
+};
 pub use self::sled::SledKvsEngine;
 
 mod kvs;
+mod memory;
+mod registry;
 mod sled;
@@ -0,0 +1,115 @@
+//! Engines selectable by name at runtime.
+//!
+//! A store directory remembers the persistent engine that created it in an
+//! `engine.log` marker file, so it is never opened with another engine by
+//! mistake. Engines keeping nothing on disk leave no marker.
+use super::{KvStore, KvsEngine, MemoryKvsEngine, SledKvsEngine};
+use crate::{ErrorKind, Result};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, OnceLock, RwLock};
+
+const ENGINE_MARKER: &str = "engine.log";
+
+/// Function opening an engine in a store directory.
+pub type EngineOpener = fn(&Path) -> Result<Arc<dyn KvsEngine>>;
+
+#[derive(Clone, Copy)]
+struct Entry {
+    open: EngineOpener,
+    // whether the engine stores data in the directory and needs the marker
+    persistent: bool,
+}
+
+fn registry() -> &'static RwLock<BTreeMap<String, Entry>> {
+    static REGISTRY: OnceLock<RwLock<BTreeMap<String, Entry>>> = OnceLock::new();
+    REGISTRY.get_or_init(|| {
+        let entry = |open: EngineOpener, persistent| Entry { open, persistent };
+        let mut engines = BTreeMap::new();
+        engines.insert("kvs".to_owned(), entry(open_kvs, true));
+        engines.insert("memory".to_owned(), entry(open_memory, false));
+        engines.insert("sled".to_owned(), entry(open_sled, true));
+        RwLock::new(engines)
+    })
+}
+
+fn open_kvs(path: &Path) -> Result<Arc<dyn KvsEngine>> {
+    Ok(Arc::new(KvStore::open(path)?))
+}
+
+fn open_memory(_path: &Path) -> Result<Arc<dyn KvsEngine>> {
+    Ok(Arc::new(MemoryKvsEngine::new()))
+}
+
+fn open_sled(path: &Path) -> Result<Arc<dyn KvsEngine>> {
+    Ok(Arc::new(SledKvsEngine::open(path)?))
+}
+
+/// Make an engine available to `open_engine` under `name`.
+///
+/// The registry belongs to the calling process, so a binary serving its own
+/// engine registers it and hands the opened engine to `server::serve`.
+///
+/// Return the engine previously registered under that name, which is replaced.
+pub fn register_engine(name: &str, open: EngineOpener) -> Option<EngineOpener> {
+    register(name, open, true)
+}
+
+/// Like `register_engine`, for an engine that stores nothing in the store
+/// directory. Opening it neither checks nor records the engine of the directory.
+pub fn register_volatile_engine(name: &str, open: EngineOpener) -> Option<EngineOpener> {
+    register(name, open, false)
+}
+
+fn register(name: &str, open: EngineOpener, persistent: bool) -> Option<EngineOpener> {
+    let entry = Entry { open, persistent };
+    let previous = registry().write().unwrap().insert(name.to_owned(), entry);
+    previous.map(|entry| entry.open)
+}
+
+/// Names of all registered engines, in alphabetical order.
+pub fn engine_names() -> Vec<String> {
+    registry().read().unwrap().keys().cloned().collect()
+}
+
+/// Open the engine registered as `name` in the store directory `path`.
+///
+/// The first successful open of a persistent engine records the engine in the
+/// directory.
+///
+/// # Errors
+///
+/// Return `ErrorKind::UnknownEngine` if no engine is registered as `name`, and
+/// `ErrorKind::EngineMismatch` if the directory was created by another engine.
+pub fn open_engine(name: &str, path: impl AsRef<Path>) -> Result<Arc<dyn KvsEngine>> {
+    let path = path.as_ref();
+    let entry = *registry()
+        .read()
+        .unwrap()
+        .get(name)
+        .ok_or_else(|| ErrorKind::UnknownEngine(name.to_owned()))?;
+    if !entry.persistent {
+        return (entry.open)(path);
+    }
+    let marker = path.join(ENGINE_MARKER);
+    let found = match fs::read_to_string(&marker) {
+        Ok(found) => Some(found),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+    if let Some(found) = &found {
+        if found.trim_end() != name {
+            return Err(ErrorKind::EngineMismatch {
+                requested: name.to_owned(),
+                found: found.trim_end().to_owned(),
+            });
+        }
+    }
+    let engine = (entry.open)(path)?;
+    if found.is_none() {
+        fs::write(marker, name)?;
+    }
+    Ok(engine)
+}
@@ -1,43 +1,167 @@
-use crate::{ErrorKind, KvsEngine, Result};
-use sled;
+use super::{byte_range, expires_at, now_millis, BatchOp, WriteBatch};
+use crate::{ErrorKind, KvsEngine, Result, ScanIter};
+use sled::transaction::{ConflictableTransactionError, TransactionError};
+use sled::{IVec, Transactional};
+use std::ops::Bound;
 use std::path::PathBuf;
+use std::time::Duration;
+
+const EXPIRY_TREE: &str = "expiry";
 
 /// Wrapper of `sled::Db`
+///
+/// Expiry times of keys set with a TTL live in a separate tree, which is updated
+/// in the same transaction as the values.
 #[derive(Clone)]
-pub struct SledKvsEngine(sled::Db);
+pub struct SledKvsEngine {
+    db: sled::Db,
+    expiry: sled::Tree,
+}
 
 impl SledKvsEngine {
     /// Initialize
     pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
-        Ok(Self(sled::open(path.into().join("sled-db"))?))
+        let db = sled::open(path.into().join("sled-db"))?;
+        let expiry = db.open_tree(EXPIRY_TREE)?;
+        Ok(Self { db, expiry })
+    }
+
+    fn write(&self, key: &[u8], val: &[u8], expires_at: Option<u64>) -> Result<()> {
+        (&*self.db, &self.expiry)
+            .transaction(|(db, expiry)| {
+                db.insert(key, val)?;
+                match expires_at {
+                    Some(expires_at) => expiry.insert(key, &expires_at.to_be_bytes())?,
+                    None => expiry.remove(key)?,
+                };
+                Ok(())
+            })
+            .map_err(from_transaction)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn is_expired(&self, key: &[u8]) -> Result<bool> {
+        Ok(self.expiry.get(key)?.is_some_and(|t| is_past(&t)))
     }
 }
 
 impl KvsEngine for SledKvsEngine {
-    fn set(&self, key: String, val: String) -> Result<()> {
-        self.0.insert(key, val.into_bytes())?;
-        self.0.flush()?;
+    fn set_bytes(&self, key: Vec<u8>, val: Vec<u8>) -> Result<()> {
+        self.write(&key, &val, None)
+    }
+
+    fn set_with_ttl(&self, key: String, val: String, ttl: Duration) -> Result<()> {
+        self.write(key.as_bytes(), val.as_bytes(), Some(expires_at(ttl)))
+    }
+
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if self.is_expired(&key)? {
+            return Ok(None);
+        }
+        Ok(self.db.get(key)?.map(|val| val.to_vec()))
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        (&*self.db, &self.expiry)
+            .transaction(|(db, expiry)| {
+                let expired = expiry.remove(key.as_slice())?.is_some_and(|t| is_past(&t));
+                if db.remove(key.as_slice())?.is_none() || expired {
+                    return Err(ConflictableTransactionError::Abort(ErrorKind::KeyNotFound));
+                }
+                Ok(())
+            })
+            .map_err(from_transaction)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let mut sled_batch = sled::Batch::default();
+        let mut keys = Vec::with_capacity(batch.len());
+        for op in batch.ops {
+            match op {
+                BatchOp::Put { key, val } => {
+                    sled_batch.insert(key.as_bytes(), val.into_bytes());
+                    keys.push(key);
+                }
+                BatchOp::Delete { key } => {
+                    sled_batch.remove(key.as_bytes());
+                    keys.push(key);
+                }
+            }
+        }
+        (&*self.db, &self.expiry)
+            .transaction(|(db, expiry)| {
+                db.apply_batch(&sled_batch)?;
+                for key in &keys {
+                    expiry.remove(key.as_bytes())?;
+                }
+                Ok(())
+            })
+            .map_err(from_transaction)?;
+        self.db.flush()?;
         Ok(())
     }
 
-    fn get(&self, key: String) -> Result<Option<String>> {
-        Ok(self
-            .0
-            .get(key)?
-            .map(|val| AsRef::<[u8]>::as_ref(&val).to_vec())
-            .map(String::from_utf8) // Option<Result>
-            .transpose()?) // -> Result<Option>
-                           //if let Ok(Some(val)) = self.0.get(key) {
-                           //    let val = String::from_utf8(val.to_vec())?;
-                           //    Ok(Some(val))
-                           //} else {
-                           //    Ok(None)
-                           //}
-    }
-
-    fn remove(&self, key: String) -> Result<()> {
-        self.0.remove(key)?.ok_or(ErrorKind::KeyNotFound)?;
-        self.0.flush()?;
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<()> {
+        (&*self.db, &self.expiry)
+            .transaction(|(db, expiry)| {
+                let current = match expiry.get(key.as_bytes())? {
+                    Some(t) if is_past(&t) => None,
+                    _ => db.get(key.as_bytes())?,
+                };
+                if current.as_deref() != expected.as_ref().map(String::as_bytes) {
+                    return Err(ConflictableTransactionError::Abort(
+                        ErrorKind::CompareFailed,
+                    ));
+                }
+                match &new {
+                    Some(val) => db.insert(key.as_bytes(), val.as_bytes())?,
+                    None => db.remove(key.as_bytes())?,
+                };
+                expiry.remove(key.as_bytes())?;
+                Ok(())
+            })
+            .map_err(from_transaction)?;
+        self.db.flush()?;
         Ok(())
     }
+
+    fn scan(&self, range: (Bound<String>, Bound<String>)) -> Result<ScanIter> {
+        let Some(range) = byte_range(range) else {
+            return Ok(Box::new(std::iter::empty()));
+        };
+        let engine = self.clone();
+        Ok(Box::new(self.db.range(range).filter_map(move |pair| {
+            let pair = pair.map_err(ErrorKind::from).and_then(|(key, val)| {
+                let Ok(string_key) = String::from_utf8(key.to_vec()) else {
+                    return Ok(None);
+                };
+                if engine.is_expired(&key)? {
+                    return Ok(None);
+                }
+                Ok(Some((string_key, String::from_utf8(val.to_vec())?)))
+            });
+            pair.transpose()
+        })))
+    }
+}
+
+/// Whether an expiry time stored in the expiry tree has passed.
+fn is_past(expires_at: &IVec) -> bool {
+    let expires_at = expires_at.as_ref().try_into().map(u64::from_be_bytes);
+    expires_at.is_ok_and(|expires_at| expires_at <= now_millis())
+}
+
+fn from_transaction(err: TransactionError<ErrorKind>) -> ErrorKind {
+    match err {
+        TransactionError::Abort(err) => err,
+        TransactionError::Storage(err) => err.into(),
+    }
 }
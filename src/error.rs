@@ -1,6 +1,6 @@
 use rayon::ThreadPoolBuildError;
-use sled;
 use std::io;
+use std::path::PathBuf;
 use std::str::Utf8Error;
 use std::string::FromUtf8Error;
 
@@ -25,6 +25,42 @@ pub enum ErrorKind {
     Other(String),
     /// Rayon
     Rayon(ThreadPoolBuildError),
+    /// Malformed frame or message on the wire
+    Protocol(String),
+    /// An option passed to `KvStoreOptions` is out of range
+    InvalidOption {
+        /// name of the option
+        name: &'static str,
+        /// why the value is rejected
+        reason: String,
+    },
+    /// The store directory does not exist and may not be created
+    NotFound(PathBuf),
+    /// A log record failed its checksum
+    Corruption {
+        /// log file holding the record
+        file: PathBuf,
+        /// offset of the record in the file
+        offset: u64,
+    },
+    /// The store directory is locked by another open store
+    Locked(PathBuf),
+    /// The store was opened read-only and can't be written
+    ReadOnly,
+    /// A failed write could not be rolled back, so the store refuses further
+    /// writes until it is opened again
+    Poisoned,
+    /// The current value of a compare-and-swap doesn't match the expected one
+    CompareFailed,
+    /// No engine is registered under this name
+    UnknownEngine(String),
+    /// The store directory was created by another engine
+    EngineMismatch {
+        /// engine asked for
+        requested: String,
+        /// engine recorded in the directory
+        found: String,
+    },
 }
 
 impl From<ThreadPoolBuildError> for ErrorKind {
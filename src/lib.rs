@@ -1,11 +1,19 @@
 #![deny(missing_docs)]
 //! A simple key-val db.
 
-pub use engines::{KvStore, KvsEngine, Message, SledKvsEngine};
+pub use engines::{
+    CompactionPolicy, CompactionStats, KvStore, KvStoreOptions, KvsEngine, MemoryKvsEngine,
+    Message, Response, ResponseErrorKind, ScanIter, SledKvsEngine, Snapshot, SyncPolicy,
+    WriteBatch,
+};
 pub use error::{ErrorKind, Result};
 pub use logger::Logger;
 
-mod engines;
+pub mod client;
+pub mod engines;
 mod error;
 mod logger;
+pub mod protocol;
+pub mod server;
+pub mod testing;
 pub mod thread_pool;
@@ -1,5 +1,3 @@
-use log;
-
 /// Customize logger
 pub struct Logger;
 
@@ -0,0 +1,165 @@
+//! Wire protocol shared by `kvs-server` and `kvs-client`.
+//!
+//! Every message is sent as a frame: a 4-byte big-endian payload length
+//! followed by the payload, which is a JSON-encoded message. Binary keys and
+//! values are base64 strings in the JSON.
+use crate::{ErrorKind, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io::{self, Read, Write};
+
+/// Largest payload accepted in a single frame.
+pub const MAX_FRAME_LEN: u32 = 64 * 1024 * 1024;
+
+/// Write `payload` as one frame.
+///
+/// The writer is not flushed, so several frames can be queued before a single flush.
+///
+/// # Errors
+///
+/// Return an error if the payload is larger than `MAX_FRAME_LEN` or is not written successfully.
+pub fn write_frame<W: Write>(writer: &mut W, payload: &[u8]) -> Result<()> {
+    let len = u32::try_from(payload.len())
+        .ok()
+        .filter(|&len| len <= MAX_FRAME_LEN)
+        .ok_or_else(|| {
+            ErrorKind::Protocol(format!("frame of {} bytes is too large", payload.len()))
+        })?;
+    writer.write_all(&len.to_be_bytes())?;
+    writer.write_all(payload)?;
+    Ok(())
+}
+
+/// Read one frame and return its payload.
+///
+/// # Errors
+///
+/// Return an error if the stream ends in the middle of a frame or the frame header
+/// announces a payload larger than `MAX_FRAME_LEN`.
+pub fn read_frame<R: Read>(reader: &mut R) -> Result<Vec<u8>> {
+    try_read_frame(reader)?.ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof).into())
+}
+
+/// Read one frame, or return `None` if the stream is closed before a new frame starts.
+///
+/// # Errors
+///
+/// Return an error if the stream ends in the middle of a frame or the frame header
+/// announces a payload larger than `MAX_FRAME_LEN`.
+pub fn try_read_frame<R: Read>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut header = [0; 4];
+    let mut filled = 0;
+    while filled < header.len() {
+        match reader.read(&mut header[filled..]) {
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let len = u32::from_be_bytes(header);
+    if len > MAX_FRAME_LEN {
+        return Err(ErrorKind::Protocol(format!(
+            "frame of {} bytes is too large",
+            len
+        )));
+    }
+    // grow the buffer while reading so a bare header can't reserve the whole
+    // frame up front
+    let mut payload = Vec::new();
+    reader.take(len as u64).read_to_end(&mut payload)?;
+    if payload.len() < len as usize {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(Some(payload))
+}
+
+/// Serialize `msg` and write it as one frame without flushing.
+///
+/// # Errors
+///
+/// Return an error if the message cannot be serialized or written.
+pub fn write_message<W: Write, T: Serialize>(writer: &mut W, msg: &T) -> Result<()> {
+    write_frame(writer, &serde_json::to_vec(msg)?)
+}
+
+/// Serialize `msg`, write it as one frame and flush the writer.
+///
+/// # Errors
+///
+/// Return an error if the message cannot be serialized or written.
+pub fn send<W: Write, T: Serialize>(writer: &mut W, msg: &T) -> Result<()> {
+    write_message(writer, msg)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Read one frame and deserialize its payload.
+///
+/// # Errors
+///
+/// Return `ErrorKind::Protocol` if the payload is not a valid message.
+pub fn receive<R: Read, T: DeserializeOwned>(reader: &mut R) -> Result<T> {
+    decode(&read_frame(reader)?)
+}
+
+/// Read one message, or return `None` if the peer closed the stream between messages.
+///
+/// # Errors
+///
+/// Return `ErrorKind::Protocol` if the payload is not a valid message.
+pub fn try_receive<R: Read, T: DeserializeOwned>(reader: &mut R) -> Result<Option<T>> {
+    try_read_frame(reader)?
+        .map(|payload| decode(&payload))
+        .transpose()
+}
+
+fn decode<T: DeserializeOwned>(payload: &[u8]) -> Result<T> {
+    serde_json::from_slice(payload)
+        .map_err(|e| ErrorKind::Protocol(format!("malformed message: {}", e)))
+}
+
+/// Serde adapter encoding bytes as a base64 string, which takes far less room in
+/// JSON than an array of numbers.
+pub(crate) mod base64_bytes {
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        STANDARD.decode(encoded).map_err(D::Error::custom)
+    }
+
+    /// Same for optional bytes.
+    pub mod option {
+        use super::STANDARD;
+        use base64::Engine;
+        use serde::de::Error;
+        use serde::{Deserialize, Deserializer, Serializer};
+
+        pub fn serialize<S: Serializer>(
+            bytes: &Option<Vec<u8>>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            match bytes {
+                Some(bytes) => serializer.serialize_some(&STANDARD.encode(bytes)),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<Vec<u8>>, D::Error> {
+            Option::<String>::deserialize(deserializer)?
+                .map(|encoded| STANDARD.decode(encoded).map_err(D::Error::custom))
+                .transpose()
+        }
+    }
+}
@@ -0,0 +1,158 @@
+//! Server side of the wire protocol, as run by `kvs-server`.
+//!
+//! A binary serving a custom engine registers it with
+//! `engines::register_engine`, opens it with `engines::open_engine` and passes
+//! it to `serve`.
+use crate::engines::prefix_range;
+use crate::{protocol, ErrorKind, KvsEngine, Message, Response, Result};
+use std::io::{BufReader, BufWriter, Write};
+use std::net::{TcpListener, TcpStream};
+use std::ops::Bound;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Bytes of keys and values after which a scan page is cut, so the page still
+/// fits in a frame if JSON escapes every byte.
+const SCAN_PAGE_BYTES: usize = protocol::MAX_FRAME_LEN as usize / 8;
+
+/// Number of connections `kvs-server` serves at once unless told otherwise.
+pub const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+
+/// Serve the requests of every client connecting to `listener` with `store`.
+///
+/// Each connection is served on a thread of its own, so idle connections
+/// don't keep other clients waiting. At most `max_connections` connections are
+/// served at once; further ones wait in the listen backlog until one closes.
+///
+/// # Errors
+///
+/// Return an error if accepting a connection fails. Errors of single
+/// connections are logged and only close that connection.
+pub fn serve(
+    listener: TcpListener,
+    store: Arc<dyn KvsEngine>,
+    max_connections: usize,
+) -> Result<()> {
+    let open = Arc::new((Mutex::new(0), Condvar::new()));
+    loop {
+        let slot = Slot::acquire(&open, max_connections.max(1));
+        let (socket, addr) = listener.accept()?;
+        log::info!("Connection from {}", addr);
+
+        let store = Arc::clone(&store);
+        thread::spawn(move || {
+            let _slot = slot;
+            if let Err(e) = job(&*store, socket) {
+                log::info!("Job error: {:?}", e);
+            }
+        });
+    }
+}
+
+/// One of the connections `serve` may have open, given back when dropped.
+struct Slot(Arc<(Mutex<usize>, Condvar)>);
+
+impl Slot {
+    fn acquire(open: &Arc<(Mutex<usize>, Condvar)>, max: usize) -> Slot {
+        let (count, freed) = &**open;
+        let mut count = count.lock().unwrap_or_else(|e| e.into_inner());
+        while *count >= max {
+            count = freed.wait(count).unwrap_or_else(|e| e.into_inner());
+        }
+        *count += 1;
+        Slot(Arc::clone(open))
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let (count, freed) = &*self.0;
+        *count.lock().unwrap_or_else(|e| e.into_inner()) -= 1;
+        freed.notify_one();
+    }
+}
+
+fn job(store: &dyn KvsEngine, socket: TcpStream) -> Result<()> {
+    let mut reader = BufReader::new(socket.try_clone()?);
+    let mut writer = BufWriter::new(socket);
+
+    // serve requests in order until the client closes the connection
+    loop {
+        // pipelined requests are answered in order and flushed together
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+
+        let msg: Message = match protocol::try_receive(&mut reader) {
+            Ok(Some(msg)) => msg,
+            Ok(None) => return Ok(()),
+            Err(e @ ErrorKind::Protocol(_)) => {
+                return protocol::send(&mut writer, &Response::from(e));
+            }
+            Err(e) => return Err(e),
+        };
+        log::info!("{:?}", msg);
+
+        match protocol::write_message(&mut writer, &handle(store, msg)) {
+            // e.g. a single pair too large for a frame
+            Err(e @ ErrorKind::Protocol(_)) => {
+                protocol::write_message(&mut writer, &Response::from(e))?
+            }
+            res => res?,
+        }
+    }
+}
+
+fn handle(store: &dyn KvsEngine, msg: Message) -> Response {
+    let resp = match msg {
+        Message::Get { key } => store.get(key).map(Response::Value),
+        Message::Set { key, val } => store.set(key, val).map(|_| Response::Ok),
+        Message::SetWithTtl { key, val, ttl_ms } => store
+            .set_with_ttl(key, val, Duration::from_millis(ttl_ms))
+            .map(|_| Response::Ok),
+        Message::Rm { key } => store.remove(key).map(|_| Response::Ok),
+        Message::GetBytes { key } => store.get_bytes(key).map(Response::Bytes),
+        Message::SetBytes { key, val } => store.set_bytes(key, val).map(|_| Response::Ok),
+        Message::RmBytes { key } => store.remove_bytes(key).map(|_| Response::Ok),
+        Message::Cas { key, expected, new } => store
+            .compare_and_swap(key, expected, new)
+            .map(|_| Response::Ok),
+        Message::Scan { start, end, limit } => store
+            .scan((start, end))
+            .and_then(|iter| scan_page(iter, limit)),
+        Message::ScanPrefix {
+            prefix,
+            after,
+            limit,
+        } => {
+            let (start, end) = prefix_range(&prefix);
+            let start = after.map_or(start, Bound::Excluded);
+            store
+                .scan((start, end))
+                .and_then(|iter| scan_page(iter, limit))
+        }
+    };
+    resp.unwrap_or_else(Response::from)
+}
+
+/// Collect the next page of a scan, stopping after `limit` pairs or once the
+/// page is large enough.
+fn scan_page(
+    iter: impl Iterator<Item = Result<(String, String)>>,
+    limit: Option<usize>,
+) -> Result<Response> {
+    let limit = limit.unwrap_or(usize::MAX).max(1);
+    let mut pairs: Vec<(String, String)> = Vec::new();
+    let mut bytes = 0;
+    for pair in iter {
+        if pairs.len() >= limit || bytes >= SCAN_PAGE_BYTES {
+            let next = pairs.last().map(|(key, _)| key.clone());
+            return Ok(Response::Pairs { pairs, next });
+        }
+        let (key, val) = pair?;
+        bytes += key.len() + val.len();
+        pairs.push((key, val));
+    }
+    Ok(Response::Pairs { pairs, next: None })
+}
@@ -0,0 +1,567 @@
+//! Conformance suite every `KvsEngine` is expected to pass.
+//!
+//! Third-party engines implement `ConformanceEngine` and call `conformance` from
+//! a test:
+//!
+//! ```rust
+//! # use kvs::{testing, KvStore};
+//! testing::conformance::<KvStore>().unwrap();
+//! ```
+//!
+//! `FaultInjector` makes the file writes of a `KvStore` fail at chosen points, to
+//! test what a restart after a crash finds.
+pub use crate::engines::{CrashPoint, FaultInjector};
+use crate::{
+    ErrorKind, KvStore, KvsEngine, MemoryKvsEngine, Result, ScanIter, SledKvsEngine, WriteBatch,
+};
+use std::env;
+use std::fs;
+use std::io;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+const THREADS: usize = 8;
+const KEYS_PER_THREAD: usize = 100;
+
+/// An engine the conformance suite knows how to open.
+pub trait ConformanceEngine: KvsEngine + Sized {
+    /// Whether the data survives dropping every handle and opening the engine
+    /// again. Persistence and crash recovery are only checked if it does.
+    const PERSISTENT: bool = true;
+
+    /// Open the engine in the directory `path`, which is either empty or holds
+    /// the data of an earlier open.
+    ///
+    /// # Errors
+    ///
+    /// Return an error if the engine can't be opened.
+    fn open(path: &Path) -> Result<Self>;
+}
+
+impl ConformanceEngine for KvStore {
+    fn open(path: &Path) -> Result<Self> {
+        KvStore::open(path)
+    }
+}
+
+impl ConformanceEngine for SledKvsEngine {
+    fn open(path: &Path) -> Result<Self> {
+        // sled's background threads release the file lock of a dropped database
+        // a moment later, so an immediate reopen may find it still taken
+        for _ in 0..50 {
+            match SledKvsEngine::open(path) {
+                Err(ErrorKind::Sled(sled::Error::Io(_))) => {
+                    thread::sleep(Duration::from_millis(10))
+                }
+                res => return res,
+            }
+        }
+        SledKvsEngine::open(path)
+    }
+}
+
+impl ConformanceEngine for MemoryKvsEngine {
+    const PERSISTENT: bool = false;
+
+    fn open(_path: &Path) -> Result<Self> {
+        Ok(MemoryKvsEngine::new())
+    }
+}
+
+/// Run the whole conformance suite against engine `E`.
+///
+/// Every check opens the engine in a fresh directory under the system temporary
+/// directory, which is removed afterwards.
+///
+/// # Panics
+///
+/// Panic if the engine misbehaves, naming the failed check.
+///
+/// # Errors
+///
+/// Return an error if an operation the engine must support fails.
+pub fn conformance<E: ConformanceEngine>() -> Result<()> {
+    overwrite::<E>()?;
+    remove::<E>()?;
+    bytes::<E>()?;
+    scan::<E>()?;
+    write_batch::<E>()?;
+    compare_and_swap::<E>()?;
+    ttl::<E>()?;
+    concurrency::<E>()?;
+    if E::PERSISTENT {
+        persistence::<E>()?;
+        crash_recovery::<E>()?;
+    }
+    Ok(())
+}
+
+/// A later write of a key replaces the earlier one.
+pub fn overwrite<E: ConformanceEngine>() -> Result<()> {
+    let dir = ScratchDir::new()?;
+    let engine = E::open(dir.path())?;
+    engine.set("key".to_owned(), "value1".to_owned())?;
+    engine.set("key".to_owned(), "value2".to_owned())?;
+    assert_eq!(
+        engine.get("key".to_owned())?,
+        Some("value2".to_owned()),
+        "overwrite: string value not replaced"
+    );
+    engine.set_bytes(vec![0xff], vec![0x00])?;
+    engine.set_bytes(vec![0xff], vec![0x01, 0x02])?;
+    assert_eq!(
+        engine.get_bytes(vec![0xff])?,
+        Some(vec![0x01, 0x02]),
+        "overwrite: byte value not replaced"
+    );
+    assert_eq!(
+        engine.get("missing".to_owned())?,
+        None,
+        "overwrite: missing key has a value"
+    );
+    Ok(())
+}
+
+/// Removed keys read as missing, and removing a missing key fails.
+pub fn remove<E: ConformanceEngine>() -> Result<()> {
+    let dir = ScratchDir::new()?;
+    let engine = E::open(dir.path())?;
+    assert!(
+        matches!(engine.remove("key".to_owned()), Err(ErrorKind::KeyNotFound)),
+        "remove: removing a missing key succeeded"
+    );
+    engine.set("key".to_owned(), "value".to_owned())?;
+    engine.remove("key".to_owned())?;
+    assert_eq!(
+        engine.get("key".to_owned())?,
+        None,
+        "remove: removed key still has a value"
+    );
+    assert!(
+        matches!(engine.remove("key".to_owned()), Err(ErrorKind::KeyNotFound)),
+        "remove: removing a key twice succeeded"
+    );
+    engine.set("key".to_owned(), "again".to_owned())?;
+    assert_eq!(
+        engine.get("key".to_owned())?,
+        Some("again".to_owned()),
+        "remove: removed key can't be set again"
+    );
+    Ok(())
+}
+
+/// Keys and values are arbitrary bytes, and the string methods read and write
+/// the same data.
+pub fn bytes<E: ConformanceEngine>() -> Result<()> {
+    let dir = ScratchDir::new()?;
+    let engine = E::open(dir.path())?;
+    let key = vec![0xff, 0x00, 0xfe];
+    let val = vec![0x80, 0x00, 0x0a, 0xc3];
+    engine.set_bytes(key.clone(), val.clone())?;
+    assert_eq!(
+        engine.get_bytes(key.clone())?,
+        Some(val),
+        "bytes: binary value lost"
+    );
+
+    // the string API reads keys set as bytes, but refuses values that aren't UTF-8
+    engine.set_bytes(b"key".to_vec(), vec![0xc3, 0x28])?;
+    assert!(
+        matches!(engine.get("key".to_owned()), Err(ErrorKind::String(_))),
+        "bytes: value that isn't UTF-8 read as a string"
+    );
+    engine.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(
+        engine.get_bytes(b"key".to_vec())?,
+        Some(b"value".to_vec()),
+        "bytes: string value not readable as bytes"
+    );
+
+    engine.remove_bytes(key.clone())?;
+    assert_eq!(
+        engine.get_bytes(key.clone())?,
+        None,
+        "bytes: removed key still has a value"
+    );
+    assert!(
+        matches!(engine.remove_bytes(key), Err(ErrorKind::KeyNotFound)),
+        "bytes: removing a missing key succeeded"
+    );
+    Ok(())
+}
+
+/// Scans return the keys of a range or prefix in order, skipping removed keys
+/// and keys that aren't strings.
+pub fn scan<E: ConformanceEngine>() -> Result<()> {
+    let dir = ScratchDir::new()?;
+    let engine = E::open(dir.path())?;
+    for key in ["b", "a2", "c", "a1", "a", "ab"] {
+        engine.set(key.to_owned(), format!("{}-val", key))?;
+    }
+    engine.remove("a2".to_owned())?;
+    engine.set_bytes(vec![b'a', 0xff], b"binary".to_vec())?;
+    let keys = |iter: ScanIter| -> Result<Vec<String>> { iter.map(|pair| Ok(pair?.0)).collect() };
+
+    let mut all = engine.scan((Bound::Unbounded, Bound::Unbounded))?;
+    assert_eq!(
+        all.next().transpose()?,
+        Some(("a".to_owned(), "a-val".to_owned())),
+        "scan: wrong first pair"
+    );
+    assert_eq!(
+        keys(all)?,
+        ["a1", "ab", "b", "c"],
+        "scan: wrong keys of a full scan"
+    );
+    let range = engine.scan((
+        Bound::Included("a1".to_owned()),
+        Bound::Excluded("c".to_owned()),
+    ))?;
+    assert_eq!(
+        keys(range)?,
+        ["a1", "ab", "b"],
+        "scan: wrong keys of a range"
+    );
+    assert_eq!(
+        keys(engine.scan_prefix("a".to_owned())?)?,
+        ["a", "a1", "ab"],
+        "scan: wrong keys of a prefix"
+    );
+
+    // reversed bounds hold no keys instead of panicking
+    let reversed = engine.scan((
+        Bound::Included("c".to_owned()),
+        Bound::Included("a".to_owned()),
+    ))?;
+    assert_eq!(reversed.count(), 0, "scan: reversed range holds keys");
+    Ok(())
+}
+
+/// A batch applies its writes in order, and deleting a missing key doesn't
+/// fail it.
+pub fn write_batch<E: ConformanceEngine>() -> Result<()> {
+    let dir = ScratchDir::new()?;
+    let engine = E::open(dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.put("key2".to_owned(), "value2".to_owned());
+    batch.delete("key1".to_owned());
+    batch.put("key3".to_owned(), "value3".to_owned());
+    batch.put("key2".to_owned(), "value4".to_owned());
+    batch.delete("key5".to_owned());
+    engine.write_batch(batch)?;
+
+    assert_eq!(
+        engine.get("key1".to_owned())?,
+        None,
+        "write batch: deleted key still has a value"
+    );
+    assert_eq!(
+        engine.get("key2".to_owned())?,
+        Some("value4".to_owned()),
+        "write batch: later put of a key not applied last"
+    );
+    assert_eq!(
+        engine.get("key3".to_owned())?,
+        Some("value3".to_owned()),
+        "write batch: put lost"
+    );
+    engine.remove("key3".to_owned())?;
+    assert_eq!(
+        engine.get("key3".to_owned())?,
+        None,
+        "write batch: key put by a batch can't be removed"
+    );
+    Ok(())
+}
+
+/// Compare-and-swap only writes if the current value is the expected one,
+/// with `None` standing for a missing key.
+pub fn compare_and_swap<E: ConformanceEngine>() -> Result<()> {
+    let dir = ScratchDir::new()?;
+    let engine = E::open(dir.path())?;
+    let failed = |res| matches!(res, Err(ErrorKind::CompareFailed));
+
+    // create only if missing
+    engine.compare_and_swap("key1".to_owned(), None, Some("value1".to_owned()))?;
+    assert!(
+        failed(engine.compare_and_swap("key1".to_owned(), None, Some("value2".to_owned()))),
+        "compare-and-swap: existing key created again"
+    );
+    assert_eq!(
+        engine.get("key1".to_owned())?,
+        Some("value1".to_owned()),
+        "compare-and-swap: failed swap wrote"
+    );
+
+    engine.compare_and_swap(
+        "key1".to_owned(),
+        Some("value1".to_owned()),
+        Some("value2".to_owned()),
+    )?;
+    assert!(
+        failed(engine.compare_and_swap("key1".to_owned(), Some("value1".to_owned()), None)),
+        "compare-and-swap: stale expected value accepted"
+    );
+    engine.compare_and_swap("key1".to_owned(), Some("value2".to_owned()), None)?;
+    assert_eq!(
+        engine.get("key1".to_owned())?,
+        None,
+        "compare-and-swap: key not removed"
+    );
+
+    // a missing key stays missing
+    engine.compare_and_swap("key1".to_owned(), None, None)?;
+    engine.set("key2".to_owned(), "value".to_owned())?;
+    assert!(
+        failed(engine.compare_and_swap("key2".to_owned(), None, None)),
+        "compare-and-swap: existing key taken for missing"
+    );
+    Ok(())
+}
+
+/// Keys set with a TTL read as missing once it has passed, and setting a key
+/// without a TTL makes it permanent.
+pub fn ttl<E: ConformanceEngine>() -> Result<()> {
+    let dir = ScratchDir::new()?;
+    let engine = E::open(dir.path())?;
+    // keys checked before they expire get a TTL long enough for slow disks
+    let short = Duration::from_millis(200);
+    let long = Duration::from_secs(2);
+    engine.set_with_ttl("key1".to_owned(), "value1".to_owned(), short)?;
+    engine.set_with_ttl("key2".to_owned(), "value2".to_owned(), short)?;
+    engine.set_with_ttl("key3".to_owned(), "value3".to_owned(), short)?;
+    engine.set_with_ttl("key4".to_owned(), "value4".to_owned(), long)?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(
+        engine.get("key4".to_owned())?,
+        Some("value4".to_owned()),
+        "ttl: key expired early"
+    );
+
+    thread::sleep(short + Duration::from_millis(100));
+    assert_eq!(
+        engine.get("key1".to_owned())?,
+        None,
+        "ttl: expired key still has a value"
+    );
+    assert_eq!(
+        engine.get("key2".to_owned())?,
+        Some("value2".to_owned()),
+        "ttl: key set without a TTL expired"
+    );
+    assert!(
+        matches!(
+            engine.remove("key1".to_owned()),
+            Err(ErrorKind::KeyNotFound)
+        ),
+        "ttl: removing an expired key succeeded"
+    );
+    engine.compare_and_swap("key3".to_owned(), None, Some("value4".to_owned()))?;
+    assert_eq!(
+        engine.get("key3".to_owned())?,
+        Some("value4".to_owned()),
+        "ttl: expired key not missing for compare-and-swap"
+    );
+    let keys: Vec<String> = engine
+        .scan((Bound::Unbounded, Bound::Unbounded))?
+        .map(|pair| pair.map(|(key, _)| key))
+        .collect::<Result<_>>()?;
+    assert_eq!(
+        keys,
+        ["key2", "key3", "key4"],
+        "ttl: scan returned expired keys"
+    );
+    Ok(())
+}
+
+/// Data written before every handle is dropped is there after opening again.
+pub fn persistence<E: ConformanceEngine>() -> Result<()> {
+    let dir = ScratchDir::new()?;
+    let engine = E::open(dir.path())?;
+    write_history(&engine)?;
+    drop(engine);
+
+    for _ in 0..2 {
+        let engine = E::open(dir.path())?;
+        check_history(&engine, "persistence")?;
+    }
+    Ok(())
+}
+
+/// Writes from many threads don't get lost, and compare-and-swap is atomic.
+pub fn concurrency<E: ConformanceEngine>() -> Result<()> {
+    let dir = ScratchDir::new()?;
+    let engine = Arc::new(E::open(dir.path())?);
+    engine.set("counter".to_owned(), "0".to_owned())?;
+    let handles: Vec<_> = (0..THREADS)
+        .map(|t| {
+            let engine = Arc::clone(&engine);
+            thread::spawn(move || -> Result<()> {
+                for i in 0..KEYS_PER_THREAD {
+                    let key = format!("key{}-{}", t, i);
+                    engine.set(key.clone(), format!("value{}", i))?;
+                    assert_eq!(
+                        engine.get(key)?,
+                        Some(format!("value{}", i)),
+                        "concurrency: own write not visible"
+                    );
+                    increment(&*engine)?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().expect("conformance thread panicked")?;
+    }
+
+    for t in 0..THREADS {
+        for i in 0..KEYS_PER_THREAD {
+            assert_eq!(
+                engine.get(format!("key{}-{}", t, i))?,
+                Some(format!("value{}", i)),
+                "concurrency: write lost"
+            );
+        }
+    }
+    assert_eq!(
+        engine.get("counter".to_owned())?,
+        Some((THREADS * KEYS_PER_THREAD).to_string()),
+        "concurrency: compare-and-swap lost an increment"
+    );
+    Ok(())
+}
+
+/// Every acknowledged write survives a crash.
+///
+/// The crash is simulated by copying the store directory while the engine is
+/// still open, so nothing gets the chance to shut down cleanly, and opening the
+/// copy.
+pub fn crash_recovery<E: ConformanceEngine>() -> Result<()> {
+    let dir = ScratchDir::new()?;
+    let engine = E::open(dir.path())?;
+    write_history(&engine)?;
+
+    let crashed = ScratchDir::new()?;
+    copy_dir(dir.path(), crashed.path())?;
+    let recovered = E::open(crashed.path())?;
+    check_history(&recovered, "crash recovery")?;
+
+    // the recovered store keeps working and persists new writes
+    recovered.set("after".to_owned(), "crash".to_owned())?;
+    drop(recovered);
+    let recovered = E::open(crashed.path())?;
+    check_history(&recovered, "crash recovery")?;
+    assert_eq!(
+        recovered.get("after".to_owned())?,
+        Some("crash".to_owned()),
+        "crash recovery: write after recovery lost"
+    );
+    drop(engine);
+    Ok(())
+}
+
+/// Write keys of which some are overwritten and some removed afterwards.
+fn write_history(engine: &impl KvsEngine) -> Result<()> {
+    for i in 0..KEYS_PER_THREAD {
+        engine.set(format!("key{}", i), format!("old{}", i))?;
+    }
+    for i in (0..KEYS_PER_THREAD).step_by(2) {
+        engine.set(format!("key{}", i), format!("new{}", i))?;
+    }
+    for i in (0..KEYS_PER_THREAD).step_by(3) {
+        engine.remove(format!("key{}", i))?;
+    }
+    engine.set_bytes(vec![0xff, 0x00], vec![0xfe])?;
+    Ok(())
+}
+
+/// Check the state `write_history` leaves behind.
+fn check_history(engine: &impl KvsEngine, check: &str) -> Result<()> {
+    for i in 0..KEYS_PER_THREAD {
+        let expected = if i % 3 == 0 {
+            None
+        } else if i % 2 == 0 {
+            Some(format!("new{}", i))
+        } else {
+            Some(format!("old{}", i))
+        };
+        assert_eq!(
+            engine.get(format!("key{}", i))?,
+            expected,
+            "{}: wrong value of key{}",
+            check,
+            i
+        );
+    }
+    assert_eq!(
+        engine.get_bytes(vec![0xff, 0x00])?,
+        Some(vec![0xfe]),
+        "{}: byte value lost",
+        check
+    );
+    Ok(())
+}
+
+/// Increment the counter with a compare-and-swap loop.
+fn increment(engine: &impl KvsEngine) -> Result<()> {
+    loop {
+        let current = engine.get("counter".to_owned())?;
+        let next = current
+            .as_deref()
+            .and_then(|n| n.parse::<usize>().ok())
+            .expect("concurrency: counter is not a number")
+            + 1;
+        match engine.compare_and_swap("counter".to_owned(), current, Some(next.to_string())) {
+            Ok(()) => return Ok(()),
+            Err(ErrorKind::CompareFailed) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn copy_dir(from: &Path, to: &Path) -> io::Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
+}
+
+/// A fresh directory under the system temporary directory, removed when dropped.
+struct ScratchDir(PathBuf);
+
+impl ScratchDir {
+    fn new() -> io::Result<ScratchDir> {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let path = env::temp_dir().join(format!(
+            "kvs-conformance-{}-{}",
+            process::id(),
+            NEXT.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::create_dir_all(&path)?;
+        Ok(ScratchDir(path))
+    }
+
+    fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
@@ -17,6 +17,6 @@ impl ThreadPool for RayonThreadPool {
     where
         F: FnOnce() + Send + 'static,
     {
-        self.0.install(|| job());
+        self.0.install(job);
     }
 }
@@ -23,7 +23,7 @@ impl ThreadPool for SharedQueueThreadPool {
 
         Ok(Self {
             sender: Some(tx),
-            pool: pool,
+            pool,
         })
     }
 
@@ -97,7 +97,7 @@ impl Worker {
             }
         });
         Self {
-            id: id,
+            id,
             handle: Some(handle),
         }
     }
@@ -0,0 +1,380 @@
+use assert_cmd::prelude::*;
+use kvs::client::KvsClient;
+use kvs::{engines, server, ErrorKind, KvsEngine, MemoryKvsEngine, Message, Response, Result};
+use std::io;
+use std::net::{TcpListener, TcpStream};
+use std::ops::Bound;
+use std::path::Path;
+use std::process::{Child, Command};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Kill the server when the test ends, even if it panics.
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.0.kill().expect("server exited before killed");
+        self.0.wait().expect("server was not reaped");
+    }
+}
+
+fn start_server(temp_dir: &TempDir, addr: &str) -> Server {
+    start_server_with_engine(temp_dir, "kvs", addr)
+}
+
+fn start_server_with_engine(temp_dir: &TempDir, engine: &str, addr: &str) -> Server {
+    let child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", engine, "--addr", addr])
+        .current_dir(temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Server(child)
+}
+
+// Many requests should be served on a single connection.
+#[test]
+fn persistent_connection() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let _server = start_server(&temp_dir, "127.0.0.1:4010");
+
+    let mut client = KvsClient::connect("127.0.0.1:4010")?;
+    for i in 0..100 {
+        let resp = client.request(&Message::Set {
+            key: format!("key{}", i),
+            val: format!("value{}", i),
+        })?;
+        assert_eq!(resp, Response::Ok);
+    }
+    for i in 0..100 {
+        let resp = client.request(&Message::Get {
+            key: format!("key{}", i),
+        })?;
+        assert_eq!(resp, Response::Value(Some(format!("value{}", i))));
+    }
+    Ok(())
+}
+
+#[test]
+fn client_api() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let _server = start_server(&temp_dir, "127.0.0.1:4011");
+
+    let mut client = KvsClient::connect("127.0.0.1:4011")?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.set("key1".to_owned(), "Key not found".to_owned())?;
+    assert_eq!(
+        client.get("key1".to_owned())?,
+        Some("Key not found".to_owned())
+    );
+    assert_eq!(client.get("key2".to_owned())?, None);
+
+    client.remove("key1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    assert!(matches!(
+        client.remove("key1".to_owned()),
+        Err(ErrorKind::KeyNotFound)
+    ));
+    Ok(())
+}
+
+// Pipelined responses must come back in request order, even with several
+// connections served concurrently by the server.
+#[test]
+fn pipeline_preserves_order() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let _server = start_server(&temp_dir, "127.0.0.1:4012");
+
+    let handles: Vec<_> = (0..8)
+        .map(|client_id| {
+            thread::spawn(move || -> Result<()> {
+                let mut client = KvsClient::connect("127.0.0.1:4012")?;
+                let mut msgs = Vec::new();
+                for i in 0..1000 {
+                    let key = format!("client{}-key{}", client_id, i % 10);
+                    msgs.push(Message::Set {
+                        key: key.clone(),
+                        val: format!("value{}", i),
+                    });
+                    msgs.push(Message::Get { key });
+                }
+                msgs.push(Message::Rm {
+                    key: "missing".to_owned(),
+                });
+
+                let resps = client.pipeline(&msgs)?;
+                assert_eq!(resps.len(), msgs.len());
+                for (i, pair) in resps[..2000].chunks(2).enumerate() {
+                    assert_eq!(pair[0], Response::Ok);
+                    assert_eq!(pair[1], Response::Value(Some(format!("value{}", i))));
+                }
+                assert!(matches!(resps[2000], Response::Error { .. }));
+
+                // the connection is still usable after a pipeline
+                assert_eq!(
+                    client.get(format!("client{}-key9", client_id))?,
+                    Some("value999".to_owned())
+                );
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    Ok(())
+}
+
+#[test]
+fn scan_over_the_wire() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let _server = start_server(&temp_dir, "127.0.0.1:4013");
+
+    let mut client = KvsClient::connect("127.0.0.1:4013")?;
+    for key in ["user:2", "order:1", "user:1", "user:3"] {
+        client.set(key.to_owned(), format!("{}-val", key))?;
+    }
+    let pairs = client.scan((Bound::Included("user:2".to_owned()), Bound::Unbounded))?;
+    assert_eq!(
+        pairs,
+        [
+            ("user:2".to_owned(), "user:2-val".to_owned()),
+            ("user:3".to_owned(), "user:3-val".to_owned()),
+        ]
+    );
+    assert_eq!(client.scan_prefix("order:".to_owned())?.len(), 1);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "--prefix", "user:", "--addr", "127.0.0.1:4013"])
+        .assert()
+        .success()
+        .stdout("user:1\tuser:1-val\nuser:2\tuser:2-val\nuser:3\tuser:3-val\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "--start", "order:1", "--end", "user:2"])
+        .args(["--addr", "127.0.0.1:4013"])
+        .assert()
+        .success()
+        .stdout("order:1\torder:1-val\nuser:1\tuser:1-val\n");
+    Ok(())
+}
+
+#[test]
+fn compare_and_swap_over_the_wire() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let _server = start_server(&temp_dir, "127.0.0.1:4014");
+
+    let mut client = KvsClient::connect("127.0.0.1:4014")?;
+    client.compare_and_swap("key1".to_owned(), None, Some("value1".to_owned()))?;
+    assert!(matches!(
+        client.compare_and_swap("key1".to_owned(), None, Some("value2".to_owned())),
+        Err(ErrorKind::CompareFailed)
+    ));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["cas", "key1", "--expected", "value1", "--new", "value2"])
+        .args(["--addr", "127.0.0.1:4014"])
+        .assert()
+        .success()
+        .stdout("");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["cas", "key1", "--expected", "value1"])
+        .args(["--addr", "127.0.0.1:4014"])
+        .assert()
+        .failure()
+        .stderr("Value mismatch\n");
+    assert_eq!(client.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn set_with_ttl_over_the_wire() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let _server = start_server(&temp_dir, "127.0.0.1:4015");
+
+    let mut client = KvsClient::connect("127.0.0.1:4015")?;
+    client.set_with_ttl(
+        "key1".to_owned(),
+        "value1".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "set",
+            "key2",
+            "value2",
+            "--ttl",
+            "2",
+            "--addr",
+            "127.0.0.1:4015",
+        ])
+        .assert()
+        .success();
+    assert_eq!(client.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    thread::sleep(Duration::from_millis(2200));
+    assert_eq!(client.get("key1".to_owned())?, None);
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key2", "--addr", "127.0.0.1:4015"])
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+    Ok(())
+}
+
+#[test]
+fn binary_values_over_the_wire() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let _server = start_server(&temp_dir, "127.0.0.1:4016");
+
+    let mut client = KvsClient::connect("127.0.0.1:4016")?;
+    let key = vec![0xff, 0x00, 0xfe];
+    let val = vec![0x80, 0x00, 0x0a, 0xc3];
+    client.set_bytes(key.clone(), val.clone())?;
+    assert_eq!(client.get_bytes(key.clone())?, Some(val));
+    client.remove_bytes(key.clone())?;
+    assert_eq!(client.get_bytes(key.clone())?, None);
+    assert!(matches!(
+        client.remove_bytes(key),
+        Err(ErrorKind::KeyNotFound)
+    ));
+    Ok(())
+}
+
+#[test]
+fn memory_engine_over_the_wire() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let _server = start_server_with_engine(&temp_dir, "memory", "127.0.0.1:4017");
+
+    let mut client = KvsClient::connect("127.0.0.1:4017")?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.remove("key1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// Idle connections, even more of them than the server has pool threads, must not
+// keep other clients from being served.
+#[test]
+fn idle_connections_dont_block_others() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let _server = start_server(&temp_dir, "127.0.0.1:4018");
+
+    let _idle = (0..8)
+        .map(|_| TcpStream::connect("127.0.0.1:4018"))
+        .collect::<io::Result<Vec<_>>>()?;
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let res = KvsClient::connect("127.0.0.1:4018").and_then(|mut client| {
+            client.set("key1".to_owned(), "value1".to_owned())?;
+            client.get("key1".to_owned())
+        });
+        let _ = tx.send(res);
+    });
+    let val = rx
+        .recv_timeout(Duration::from_secs(5))
+        .expect("request not served while other connections are idle")?;
+    assert_eq!(val, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Scans are answered in pages, which the client follows until the end.
+#[test]
+fn scan_pages_over_the_wire() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let _server = start_server(&temp_dir, "127.0.0.1:4019");
+
+    let mut client = KvsClient::connect("127.0.0.1:4019")?;
+    for i in 0..10 {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    let (pairs, next) = client.scan_page(&Message::Scan {
+        start: Bound::Unbounded,
+        end: Bound::Unbounded,
+        limit: Some(3),
+    })?;
+    assert_eq!(pairs.len(), 3);
+    assert_eq!(next, Some("key2".to_owned()));
+    let (pairs, next) = client.scan_page(&Message::ScanPrefix {
+        prefix: "key".to_owned(),
+        after: next,
+        limit: Some(3),
+    })?;
+    assert_eq!(pairs[0], ("key3".to_owned(), "value3".to_owned()));
+    assert_eq!(next, Some("key5".to_owned()));
+    let (pairs, next) = client.scan_page(&Message::Scan {
+        start: Bound::Excluded("key5".to_owned()),
+        end: Bound::Unbounded,
+        limit: Some(10),
+    })?;
+    assert_eq!(pairs.len(), 4);
+    assert_eq!(next, None);
+
+    // a result larger than a page comes back whole
+    let big = "v".repeat(4 * 1024 * 1024);
+    for i in 0..3 {
+        client.set(format!("big{}", i), big.clone())?;
+    }
+    let pairs = client.scan_prefix("big".to_owned())?;
+    assert_eq!(pairs.len(), 3);
+    assert!(pairs.iter().all(|(_, val)| *val == big));
+    assert_eq!(client.scan((Bound::Unbounded, Bound::Unbounded))?.len(), 13);
+    Ok(())
+}
+
+fn open_custom(_path: &Path) -> Result<Arc<dyn KvsEngine>> {
+    Ok(Arc::new(MemoryKvsEngine::new()))
+}
+
+// A binary of its own can serve an engine it registers, without kvs-server.
+#[test]
+fn serve_registered_engine() -> Result<()> {
+    engines::register_volatile_engine("custom", open_custom);
+    let temp_dir = TempDir::new().unwrap();
+    let store = engines::open_engine("custom", temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    thread::spawn(move || server::serve(listener, store, server::DEFAULT_MAX_CONNECTIONS));
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(client.scan_prefix("key".to_owned())?.len(), 1);
+    Ok(())
+}
+
+// Connections beyond the cap wait until an open one closes.
+#[test]
+fn connections_beyond_the_cap_wait() -> Result<()> {
+    let store: Arc<dyn KvsEngine> = Arc::new(MemoryKvsEngine::new());
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    thread::spawn(move || server::serve(listener, store, 1));
+
+    let mut first = KvsClient::connect(addr)?;
+    first.set("key1".to_owned(), "value1".to_owned())?;
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let res = KvsClient::connect(addr).and_then(|mut client| client.get("key1".to_owned()));
+        let _ = tx.send(res);
+    });
+    assert!(rx.recv_timeout(Duration::from_millis(300)).is_err());
+
+    drop(first);
+    let val = rx
+        .recv_timeout(Duration::from_secs(5))
+        .expect("waiting connection not served after another closed")?;
+    assert_eq!(val, Some("value1".to_owned()));
+    Ok(())
+}
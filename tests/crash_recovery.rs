@@ -0,0 +1,261 @@
+use kvs::testing::{CrashPoint, FaultInjector};
+use kvs::{CompactionPolicy, ErrorKind, KvStore, KvStoreOptions, KvsEngine, Result, WriteBatch};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+use tempfile::TempDir;
+
+const KEYS: usize = 8;
+const CRASH_POINTS: [CrashPoint; 4] = [
+    CrashPoint::CompactedLogWritten,
+    CrashPoint::CompactedLogRenamed,
+    CrashPoint::HintsWritten,
+    CrashPoint::StaleLogRemoved,
+];
+
+/// Writes applied atomically by one operation, `None` removing the key.
+type Op = Vec<(String, Option<String>)>;
+
+/// The state of the keys after every acknowledged operation, and the operation
+/// a crash interrupted, which may or may not have been applied.
+#[derive(Default)]
+struct Model {
+    acked: BTreeMap<String, String>,
+    interrupted: Option<Op>,
+}
+
+impl Model {
+    fn apply(&mut self, op: &Op) {
+        for (key, val) in op {
+            match val {
+                Some(val) => self.acked.insert(key.clone(), val.clone()),
+                None => self.acked.remove(key),
+            };
+        }
+    }
+
+    /// Check that `store` holds every acknowledged write, and the interrupted
+    /// operation either completely or not at all.
+    fn check(&self, store: &KvStore) -> Result<()> {
+        let mut with_interrupted = BTreeMap::new();
+        if let Some(op) = &self.interrupted {
+            let mut model = Model {
+                acked: self.acked.clone(),
+                interrupted: None,
+            };
+            model.apply(op);
+            with_interrupted = model.acked;
+        }
+        let mut actual = BTreeMap::new();
+        for i in 0..KEYS {
+            let key = format!("key{}", i);
+            if let Some(val) = store.get(key.clone())? {
+                actual.insert(key, val);
+            }
+        }
+        assert!(
+            actual == self.acked || (self.interrupted.is_some() && actual == with_interrupted),
+            "recovered {:?}, expected {:?} or {:?}",
+            actual,
+            self.acked,
+            with_interrupted
+        );
+        Ok(())
+    }
+}
+
+/// A fixed mix of sets, overwrites, removes and batches over a few keys. Every
+/// value is unique, so a stale value coming back is noticed.
+fn workload(seed: usize) -> Vec<Op> {
+    let mut model = BTreeMap::new();
+    let mut ops = Vec::new();
+    for i in 0..40 {
+        let key = format!("key{}", (i * 5 + seed) % KEYS);
+        let val = format!("value{}-{}", seed, i);
+        let op = match i % 5 {
+            3 if model.contains_key(&key) => vec![(key, None)],
+            4 => vec![
+                (key, Some(val.clone())),
+                (format!("key{}", (i + 1) % KEYS), None),
+                (format!("key{}", (i + 2) % KEYS), Some(val)),
+            ],
+            _ => vec![(key, Some(val))],
+        };
+        for (key, val) in &op {
+            match val {
+                Some(val) => model.insert(key.clone(), val.clone()),
+                None => model.remove(key),
+            };
+        }
+        ops.push(op);
+    }
+    ops
+}
+
+fn run(store: &KvStore, op: &Op) -> Result<()> {
+    match op.as_slice() {
+        [(key, Some(val))] => store.set(key.clone(), val.clone()),
+        [(key, None)] => store.remove(key.clone()),
+        writes => {
+            let mut batch = WriteBatch::new();
+            for (key, val) in writes {
+                match val {
+                    Some(val) => batch.put(key.clone(), val.clone()),
+                    None => batch.delete(key.clone()),
+                }
+            }
+            store.write_batch(batch)
+        }
+    }
+}
+
+/// Run `ops` until the first failure, recording what was acknowledged.
+fn run_until_crash(store: &KvStore, ops: &[Op], model: &mut Model) {
+    for op in ops {
+        match run(store, op) {
+            Ok(()) => model.apply(op),
+            Err(_) => {
+                model.interrupted = Some(op.clone());
+                return;
+            }
+        }
+    }
+}
+
+fn open_with_faults(path: &Path, faults: &Arc<FaultInjector>) -> Result<KvStore> {
+    KvStoreOptions::new()
+        .compaction(CompactionPolicy::Manual)
+        .fault_injector(Arc::clone(faults))
+        .open(path)
+}
+
+/// Reopen the store after a crash, check it, and check it again after a
+/// compaction of the recovered logs.
+fn recover(path: &Path, model: &Model) -> Result<()> {
+    let store = KvStore::open(path)?;
+    model.check(&store)?;
+    store.compact()?;
+    drop(store);
+    let store = KvStore::open(path)?;
+    model.check(&store)
+}
+
+// A crash in the middle of writing a record loses at most that record.
+#[test]
+fn torn_writes() -> Result<()> {
+    let ops = workload(0);
+    let mut budget = 0;
+    loop {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let faults = Arc::new(FaultInjector::new());
+        let store = open_with_faults(temp_dir.path(), &faults)?;
+        faults.fail_after_bytes(budget);
+        let mut model = Model::default();
+        run_until_crash(&store, &ops, &mut model);
+        let crashed = faults.has_crashed();
+        drop(store);
+        recover(temp_dir.path(), &model)?;
+        if !crashed {
+            return Ok(());
+        }
+        budget += 7;
+    }
+}
+
+// A write reported as failed must never show up, not even once later writes
+// succeed and flush the log again.
+#[test]
+fn failed_writes_are_rolled_back() -> Result<()> {
+    let ops = workload(0);
+    for failing in 0..ops.len() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let faults = Arc::new(FaultInjector::new());
+        let store = open_with_faults(temp_dir.path(), &faults)?;
+        let mut model = Model::default();
+        for (i, op) in ops.iter().enumerate() {
+            if i == failing {
+                faults.fail_once_after_bytes(5);
+            }
+            match run(&store, op) {
+                Ok(()) => model.apply(op),
+                // the key may be missing because its write failed
+                Err(ErrorKind::KeyNotFound) => {}
+                Err(_) => assert_eq!(i, failing, "write after a failed one failed"),
+            }
+        }
+
+        // a failed compare-and-swap is rolled back too
+        let current = store.get("key0".to_owned())?;
+        faults.fail_once_after_bytes(5);
+        assert!(store
+            .compare_and_swap("key0".to_owned(), current, Some("swapped".to_owned()))
+            .is_err());
+        let op = vec![("key0".to_owned(), Some("after".to_owned()))];
+        run(&store, &op)?;
+        model.apply(&op);
+
+        assert!(!faults.has_crashed());
+        drop(store);
+        recover(temp_dir.path(), &model)?;
+    }
+    Ok(())
+}
+
+// A crash at any step of a compaction loses nothing and brings nothing back.
+#[test]
+fn crash_during_compaction() -> Result<()> {
+    for point in CRASH_POINTS {
+        for with_snapshot in [false, true] {
+            let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+            let faults = Arc::new(FaultInjector::new());
+            let mut model = Model::default();
+
+            // spread the data over several logs, so there are stale logs to remove
+            for seed in 0..3 {
+                let store = open_with_faults(temp_dir.path(), &faults)?;
+                run_until_crash(&store, &workload(seed), &mut model);
+            }
+            let store = open_with_faults(temp_dir.path(), &faults)?;
+            let snapshot = store.snapshot();
+            run_until_crash(&store, &workload(3), &mut model);
+            if !with_snapshot {
+                drop(snapshot);
+            }
+            assert!(model.interrupted.is_none());
+
+            faults.crash_at(point);
+            assert!(store.compact().is_err(), "no crash at {:?}", point);
+            assert!(faults.has_crashed());
+            // nothing is acknowledged after the crash
+            run_until_crash(&store, &workload(4), &mut model);
+            model.interrupted = None;
+            drop(store);
+            recover(temp_dir.path(), &model)?;
+        }
+    }
+    Ok(())
+}
+
+// A crash while the compacted log is being written leaves the old logs in charge.
+#[test]
+fn torn_compaction() -> Result<()> {
+    let mut budget = 0;
+    loop {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let faults = Arc::new(FaultInjector::new());
+        let mut model = Model::default();
+        for seed in 0..2 {
+            let store = open_with_faults(temp_dir.path(), &faults)?;
+            run_until_crash(&store, &workload(seed), &mut model);
+        }
+        let store = open_with_faults(temp_dir.path(), &faults)?;
+        faults.fail_after_bytes(budget);
+        let compacted = store.compact().is_ok();
+        drop(store);
+        recover(temp_dir.path(), &model)?;
+        if compacted {
+            return Ok(());
+        }
+        budget += 11;
+    }
+}
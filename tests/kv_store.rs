@@ -1,6 +1,13 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{
+    engines, testing, CompactionPolicy, CompactionStats, ErrorKind, KvStore, KvStoreOptions,
+    KvsEngine, MemoryKvsEngine, Result, SledKvsEngine, SyncPolicy, WriteBatch,
+};
+use std::fs::{self, OpenOptions};
+use std::ops::Bound;
+use std::path::Path;
 use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -209,3 +216,1088 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// Logs written in the legacy JSON format should be migrated on open.
+#[test]
+fn migrate_legacy_json_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("1.log"),
+        concat!(
+            r#"{"Set":{"key":"key1","val":"value1"}}"#,
+            r#"{"Set":{"key":"key2","val":"value2"}}"#,
+            r#"{"Remove":{"key":"key1"}}"#,
+            r#"{"Set":{"key":"key3","val":"value3"}}"#,
+        ),
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert!(!temp_dir.path().join("1.log").exists());
+    store.set("key4".to_owned(), "value4".to_owned())?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    Ok(())
+}
+
+/// Encode a record in the layout of the older format `version`.
+fn old_record(version: u32, tag: u8, key: &[u8], val: &[u8]) -> Vec<u8> {
+    let mut record = vec![tag];
+    record.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    record.extend_from_slice(&(val.len() as u32).to_le_bytes());
+    record.extend_from_slice(key);
+    record.extend_from_slice(val);
+    if version >= 2 {
+        let crc = crc32fast::hash(&record);
+        record.splice(0..0, crc.to_le_bytes());
+    }
+    record
+}
+
+// Binary logs of an older format version should be migrated on open.
+#[test]
+fn migrate_older_binary_logs() -> Result<()> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let expiring = |at: Duration, val: &[u8]| {
+        let mut body = (at.as_millis() as u64).to_le_bytes().to_vec();
+        body.extend_from_slice(val);
+        body
+    };
+    for version in [2u32, 3, 4, 5] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut content = b"KVS\0".to_vec();
+        content.extend_from_slice(&version.to_le_bytes());
+        content.extend(old_record(version, 1, b"key1", b"value1"));
+        content.extend(old_record(version, 1, b"key2", b"value2"));
+        content.extend(old_record(version, 2, b"key1", b""));
+        // batches came with version 3
+        if version >= 3 {
+            let mut batch = old_record(version, 1, b"key3", b"value3");
+            batch.extend(old_record(version, 2, b"key2", b""));
+            content.extend(old_record(version, 3, b"", &batch));
+        }
+        // TTLs came with version 4
+        if version >= 4 {
+            let short = expiring(now - Duration::from_secs(1), b"value");
+            content.extend(old_record(version, 4, b"short", &short));
+            let long = expiring(now + Duration::from_secs(3600), b"value");
+            content.extend(old_record(version, 4, b"long", &long));
+        }
+        let log = temp_dir.path().join("1.log");
+        fs::write(&log, content)?;
+
+        for _ in 0..2 {
+            let store = KvStore::open(temp_dir.path())?;
+            assert!(!log.exists());
+            assert_eq!(store.get("key1".to_owned())?, None);
+            if version >= 3 {
+                assert_eq!(store.get("key2".to_owned())?, None);
+                assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+            } else {
+                assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+            }
+            if version >= 4 {
+                assert_eq!(store.get("short".to_owned())?, None);
+                assert_eq!(store.get("long".to_owned())?, Some("value".to_owned()));
+            }
+        }
+    }
+    Ok(())
+}
+
+// A record cut off at the end of the newest log was never acknowledged and
+// should be dropped, keeping every record before it.
+#[test]
+fn truncate_torn_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let len = fs::metadata(&log)?.len();
+    OpenOptions::new()
+        .write(true)
+        .open(&log)?
+        .set_len(len - 3)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// A damaged record followed by valid ones must be reported, not skipped.
+#[test]
+fn detect_mid_file_corruption() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let mut content = fs::read(&log)?;
+    // last byte of the first value
+    let offset = content.windows(6).position(|w| w == b"value1").unwrap() + 5;
+    content[offset] ^= 0xff;
+    fs::write(&log, content)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(ErrorKind::Corruption { file, offset }) => {
+            assert_eq!(file, log);
+            assert_eq!(offset, 8);
+        }
+        Err(e) => panic!("unexpected error {:?}", e),
+        Ok(_) => panic!("corruption not detected"),
+    }
+    Ok(())
+}
+
+// A damaged length must not pass for a record cut off by a crash, which would
+// drop every record after it.
+#[test]
+fn detect_damaged_record_length() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..5 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let mut content = fs::read(&log)?;
+    // high byte of `val_len` of the first record
+    content[8 + 16] ^= 0x80;
+    fs::write(&log, content)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(ErrorKind::Corruption { file, offset }) => {
+            assert_eq!(file, log);
+            assert_eq!(offset, 8);
+        }
+        Err(e) => panic!("unexpected error {:?}", e),
+        Ok(_) => panic!("corruption not detected"),
+    }
+    Ok(())
+}
+
+#[test]
+fn detect_corruption_on_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let log = temp_dir.path().join("1.log");
+    let mut content = fs::read(&log)?;
+    let last = content.len() - 1;
+    content[last] ^= 0xff;
+    fs::write(&log, content)?;
+
+    assert!(matches!(
+        store.get("key1".to_owned()),
+        Err(ErrorKind::Corruption { .. })
+    ));
+    Ok(())
+}
+
+// Every sync policy should keep writes readable and persistent.
+#[test]
+fn sync_policies() -> Result<()> {
+    for policy in [
+        SyncPolicy::Never,
+        SyncPolicy::EveryWrite,
+        SyncPolicy::EveryN(3),
+        SyncPolicy::Interval(10),
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open_with_sync(temp_dir.path(), policy)?;
+        for i in 0..10 {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        store.remove("key0".to_owned())?;
+
+        drop(store);
+        let store = KvStore::open_with_sync(temp_dir.path(), policy)?;
+        assert_eq!(store.get("key0".to_owned())?, None);
+        for i in 1..10 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn concurrent_set_every_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_sync(temp_dir.path(), SyncPolicy::EveryWrite)?;
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for i in 0..50 {
+                    let key = format!("key{}-{}", thread_id, i);
+                    store.set(key, format!("value{}", i)).unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for thread_id in 0..8 {
+        for i in 0..50 {
+            let key = format!("key{}-{}", thread_id, i);
+            assert_eq!(store.get(key)?, Some(format!("value{}", i)));
+        }
+    }
+    Ok(())
+}
+
+// Concurrent removes of one key are committed in a single order, so exactly
+// one of them finds the key.
+#[test]
+fn concurrent_remove_same_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for round in 0..20 {
+        let key = format!("key{}", round);
+        store.set(key.clone(), "value".to_owned())?;
+        let barrier = Arc::new(Barrier::new(8));
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let store = store.clone();
+                let barrier = barrier.clone();
+                let key = key.clone();
+                thread::spawn(move || {
+                    barrier.wait();
+                    store.remove(key).is_ok()
+                })
+            })
+            .collect();
+        let removed = handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .filter(|&ok| ok)
+            .count();
+        assert_eq!(removed, 1);
+        assert_eq!(store.get(key)?, None);
+    }
+    Ok(())
+}
+
+// Writers and readers keep working while compactions run in the background.
+#[test]
+fn compaction_while_writing() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let handles: Vec<_> = (0..4)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for iter in 0..200 {
+                    for key_id in 0..100 {
+                        let key = format!("key{}-{}", thread_id, key_id);
+                        store.set(key.clone(), format!("{}", iter)).unwrap();
+                        assert_eq!(store.get(key).unwrap(), Some(format!("{}", iter)));
+                    }
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    store.compact()?;
+
+    let check = |store: &KvStore| -> Result<()> {
+        for thread_id in 0..4 {
+            for key_id in 0..100 {
+                let key = format!("key{}-{}", thread_id, key_id);
+                assert_eq!(store.get(key)?, Some("199".to_owned()));
+            }
+        }
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    check(&KvStore::open(temp_dir.path())?)
+}
+
+// A compacted log that was not finished before a crash is discarded.
+#[test]
+fn discard_unfinished_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let leftover = temp_dir.path().join("2.compact");
+    fs::write(&leftover, b"partial")?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!leftover.exists());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Wait for background compactions to show up in the stats.
+fn wait_for_compaction(store: &KvStore) -> CompactionStats {
+    for _ in 0..100 {
+        let stats = store.compaction_stats();
+        if stats.compactions > 0 {
+            return stats;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    panic!("No compaction detected");
+}
+
+fn overwrite_keys(store: &KvStore, iters: usize) -> Result<()> {
+    for iter in 0..iters {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn manual_compaction_policy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .compaction(CompactionPolicy::Manual)
+        .open(temp_dir.path())?;
+    overwrite_keys(&store, 500)?;
+    assert_eq!(store.compaction_stats(), CompactionStats::default());
+
+    store.compact()?;
+    let stats = store.compaction_stats();
+    assert_eq!(stats.compactions, 1);
+    assert!(stats.reclaimed_bytes > 0);
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("499".to_owned()));
+    }
+    Ok(())
+}
+
+#[test]
+fn stale_bytes_compaction_policy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .compaction(CompactionPolicy::StaleBytes(16 * 1024))
+        .open(temp_dir.path())?;
+    overwrite_keys(&store, 20)?;
+    assert!(wait_for_compaction(&store).reclaimed_bytes > 0);
+    Ok(())
+}
+
+#[test]
+fn stale_ratio_compaction_policy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .compaction(CompactionPolicy::StaleRatio {
+            ratio: 0.5,
+            min_stale_bytes: 1024,
+        })
+        .open(temp_dir.path())?;
+    overwrite_keys(&store, 3)?;
+    assert!(wait_for_compaction(&store).reclaimed_bytes > 0);
+    Ok(())
+}
+
+#[test]
+fn max_log_files_compaction_policy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction(CompactionPolicy::MaxLogFiles(3));
+    // every open starts a new log file
+    for _ in 0..3 {
+        let store = options.open(temp_dir.path())?;
+        store.set("key".to_owned(), "value".to_owned())?;
+    }
+    let store = options.open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    wait_for_compaction(&store);
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn invalid_options() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let cases = [
+        KvStoreOptions::new().sync(SyncPolicy::EveryN(0)),
+        KvStoreOptions::new().sync(SyncPolicy::Interval(0)),
+        KvStoreOptions::new().compaction(CompactionPolicy::StaleBytes(0)),
+        KvStoreOptions::new().compaction(CompactionPolicy::StaleRatio {
+            ratio: 1.5,
+            min_stale_bytes: 0,
+        }),
+        KvStoreOptions::new().compaction(CompactionPolicy::StaleRatio {
+            ratio: f64::NAN,
+            min_stale_bytes: 0,
+        }),
+        KvStoreOptions::new().compaction(CompactionPolicy::MaxLogFiles(1)),
+        KvStoreOptions::new().write_buffer_size(0),
+        KvStoreOptions::new().read_buffer_size(0),
+        KvStoreOptions::new().read_only(true),
+    ];
+    for options in cases {
+        assert!(matches!(
+            options.open(temp_dir.path()),
+            Err(ErrorKind::InvalidOption { .. })
+        ));
+    }
+}
+
+#[test]
+fn create_if_missing() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("store");
+    assert!(matches!(
+        KvStoreOptions::new().create_if_missing(false).open(&path),
+        Err(ErrorKind::NotFound(_))
+    ));
+
+    let store = KvStoreOptions::new()
+        .write_buffer_size(16)
+        .read_buffer_size(16)
+        .open(&path)?;
+    store.set(
+        "key1".to_owned(),
+        "a longer value than the buffers".to_owned(),
+    )?;
+    drop(store);
+
+    let store = KvStoreOptions::new().create_if_missing(false).open(&path)?;
+    assert_eq!(
+        store.get("key1".to_owned())?,
+        Some("a longer value than the buffers".to_owned())
+    );
+    Ok(())
+}
+
+// Reopening a compacted store should fill the index from the hint file
+// without reading the compacted log.
+#[test]
+fn load_index_from_hints() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .compaction(CompactionPolicy::Manual)
+        .open(temp_dir.path())?;
+    overwrite_keys(&store, 3)?;
+    store.compact()?;
+    store.remove("key0".to_owned())?;
+    store.set("key1".to_owned(), "new".to_owned())?;
+    drop(store);
+    assert!(temp_dir.path().join("2.hint").exists());
+
+    // a damaged value in the compacted log would fail a replay
+    let log = temp_dir.path().join("2.log");
+    let mut content = fs::read(&log)?;
+    let last = content.len() - 1;
+    content[last] ^= 0xff;
+    fs::write(&log, content)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("new".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn replay_log_with_damaged_hints() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .compaction(CompactionPolicy::Manual)
+        .open(temp_dir.path())?;
+    overwrite_keys(&store, 3)?;
+    store.compact()?;
+    drop(store);
+
+    let hint = temp_dir.path().join("2.hint");
+    let mut content = fs::read(&hint)?;
+    content.truncate(content.len() - 1);
+    fs::write(&hint, content)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("2".to_owned()));
+    }
+
+    // stale hints go away with their log
+    store.compact()?;
+    assert!(!hint.exists());
+    Ok(())
+}
+
+#[test]
+fn lock_store_directory() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(ErrorKind::Locked(_))
+    ));
+
+    // clones share the lock, so it is held until the last one is dropped
+    let clone = store.clone();
+    drop(store);
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(ErrorKind::Locked(_))
+    ));
+    drop(clone);
+    KvStore::open(temp_dir.path())?;
+    Ok(())
+}
+
+#[test]
+fn open_read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+
+    let list_files = || -> Result<Vec<_>> {
+        let mut files = fs::read_dir(temp_dir.path())?
+            .map(|entry| Ok(entry?.file_name()))
+            .collect::<Result<Vec<_>>>()?;
+        files.sort();
+        Ok(files)
+    };
+    let files = list_files()?;
+
+    // the writer still holds the lock
+    let reader = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(reader.get("key2".to_owned())?, None);
+    assert!(matches!(
+        reader.set("key1".to_owned(), "value2".to_owned()),
+        Err(ErrorKind::ReadOnly)
+    ));
+    assert!(matches!(
+        reader.remove("key1".to_owned()),
+        Err(ErrorKind::ReadOnly)
+    ));
+    assert!(matches!(reader.compact(), Err(ErrorKind::ReadOnly)));
+    assert_eq!(list_files()?, files);
+
+    let reader = KvStoreOptions::new()
+        .create_if_missing(false)
+        .read_only(true)
+        .open(temp_dir.path())?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(matches!(
+        reader.set("key1".to_owned(), "value2".to_owned()),
+        Err(ErrorKind::ReadOnly)
+    ));
+    assert_eq!(list_files()?, files);
+
+    let missing = temp_dir.path().join("missing");
+    assert!(matches!(
+        KvStore::open_read_only(&missing),
+        Err(ErrorKind::NotFound(_))
+    ));
+    assert!(!missing.exists());
+    Ok(())
+}
+
+// The key order survives compaction and reopening.
+#[test]
+fn scan_keys_in_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .compaction(CompactionPolicy::Manual)
+        .open(temp_dir.path())?;
+    for key in ["b", "a2", "c", "a1", "a", "ab"] {
+        store.set(key.to_owned(), format!("{}-val", key))?;
+    }
+    store.remove("a2".to_owned())?;
+
+    store.compact()?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    let keys = store
+        .scan_prefix("a".to_owned())?
+        .map(|pair| Ok(pair?.0))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys, ["a", "a1", "ab"]);
+    Ok(())
+}
+
+// A prefix scan ends before the first key past the prefix, not at the end of the keys.
+#[test]
+fn prefix_range_bounds() {
+    let range = |start: &str, end: Option<&str>| {
+        (
+            Bound::Included(start.to_owned()),
+            end.map_or(Bound::Unbounded, |end| Bound::Excluded(end.to_owned())),
+        )
+    };
+    assert_eq!(engines::prefix_range("ab"), range("ab", Some("ac")));
+    assert_eq!(engines::prefix_range(""), range("", None));
+    // chars without a successor are dropped, and surrogates are skipped
+    assert_eq!(
+        engines::prefix_range("a\u{10FFFF}"),
+        range("a\u{10FFFF}", Some("b"))
+    );
+    assert_eq!(
+        engines::prefix_range("\u{10FFFF}"),
+        range("\u{10FFFF}", None)
+    );
+    assert_eq!(
+        engines::prefix_range("\u{D7FF}"),
+        range("\u{D7FF}", Some("\u{E000}"))
+    );
+}
+
+// Scans longer than the chunks the index is read in see every key once.
+#[test]
+fn scan_across_chunks() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..1000 {
+        store.set(format!("key{:04}", i), format!("value{}", i))?;
+    }
+    let mut scan = store.scan((Bound::Unbounded, Bound::Unbounded))?;
+    assert_eq!(scan.next().transpose()?.unwrap().0, "key0000");
+    // keys removed before the scan reaches them are skipped
+    store.remove("key0500".to_owned())?;
+    let keys = scan.map(|pair| Ok(pair?.0)).collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys.len(), 998);
+    assert!(keys.windows(2).all(|w| w[0] < w[1]));
+    assert!(!keys.contains(&"key0500".to_owned()));
+    Ok(())
+}
+
+// Batches survive reopening and compaction.
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.put("key2".to_owned(), "value2".to_owned());
+    batch.delete("key1".to_owned());
+    batch.put("key2".to_owned(), "value4".to_owned());
+    store.write_batch(batch)?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value4".to_owned()));
+    store.compact()?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value4".to_owned()));
+    Ok(())
+}
+
+// A batch cut off by a crash must not be replayed partially.
+#[test]
+fn discard_torn_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.put("key2".to_owned(), "value2".to_owned());
+    batch.delete("key1".to_owned());
+    batch.put("key3".to_owned(), "value3".to_owned());
+    store.write_batch(batch)?;
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let len = fs::metadata(&log)?.len();
+    OpenOptions::new()
+        .write(true)
+        .open(&log)?
+        .set_len(len - 3)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, None);
+    Ok(())
+}
+
+// Concurrent read-modify-write loops built on compare-and-swap must not lose updates.
+#[test]
+fn concurrent_compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for _ in 0..50 {
+                    loop {
+                        let current = store.get("counter".to_owned()).unwrap();
+                        let next = current.as_ref().map_or(0, |n| n.parse::<u32>().unwrap()) + 1;
+                        match store.compare_and_swap(
+                            "counter".to_owned(),
+                            current,
+                            Some(next.to_string()),
+                        ) {
+                            Ok(()) => break,
+                            Err(ErrorKind::CompareFailed) => continue,
+                            Err(e) => panic!("{:?}", e),
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(store.get("counter".to_owned())?, Some("400".to_owned()));
+    Ok(())
+}
+
+// Expiry times must survive a restart, and compaction must drop expired keys.
+#[test]
+fn persist_and_compact_expiring_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .compaction(CompactionPolicy::Manual)
+        .open(temp_dir.path())?;
+    let short = Duration::from_millis(200);
+    store.set_with_ttl("short".to_owned(), "expired-value".to_owned(), short)?;
+    store.set_with_ttl(
+        "medium".to_owned(),
+        "value".to_owned(),
+        Duration::from_secs(2),
+    )?;
+    store.set_with_ttl(
+        "long".to_owned(),
+        "value".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("medium".to_owned())?, Some("value".to_owned()));
+    thread::sleep(short + Duration::from_millis(100));
+    assert_eq!(store.get("short".to_owned())?, None);
+
+    store.compact()?;
+    drop(store);
+    for entry in fs::read_dir(temp_dir.path())? {
+        let content = fs::read(entry?.path())?;
+        assert!(!content.windows(13).any(|w| w == b"expired-value"));
+    }
+
+    // the TTL of the remaining key comes back from the hint file
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("short".to_owned())?, None);
+    assert_eq!(store.get("long".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn snapshot_point_in_time() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("a".to_owned(), "1".to_owned())?;
+    store.set("b".to_owned(), "1".to_owned())?;
+    let snapshot = store.snapshot();
+
+    store.set("a".to_owned(), "2".to_owned())?;
+    store.remove("b".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.put("c".to_owned(), "2".to_owned());
+    batch.put("a".to_owned(), "3".to_owned());
+    store.write_batch(batch)?;
+    let later = store.snapshot();
+    store.set("a".to_owned(), "4".to_owned())?;
+
+    assert_eq!(snapshot.get("a".to_owned())?, Some("1".to_owned()));
+    assert_eq!(snapshot.get("b".to_owned())?, Some("1".to_owned()));
+    assert_eq!(snapshot.get("c".to_owned())?, None);
+    let pairs: Vec<_> = snapshot
+        .scan((Bound::Unbounded, Bound::Unbounded))?
+        .collect::<Result<_>>()?;
+    assert_eq!(
+        pairs,
+        vec![
+            ("a".to_owned(), "1".to_owned()),
+            ("b".to_owned(), "1".to_owned())
+        ]
+    );
+    assert_eq!(later.get("a".to_owned())?, Some("3".to_owned()));
+    assert_eq!(later.get("b".to_owned())?, None);
+    let pairs: Vec<_> = later.scan_prefix("c".to_owned())?.collect::<Result<_>>()?;
+    assert_eq!(pairs, vec![("c".to_owned(), "2".to_owned())]);
+    assert!(later.seq() > snapshot.seq());
+
+    assert_eq!(store.get("a".to_owned())?, Some("4".to_owned()));
+    assert_eq!(store.get("b".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn compaction_keeps_snapshot_versions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .compaction(CompactionPolicy::Manual)
+        .open(temp_dir.path())?;
+    store.set("key".to_owned(), "old-value".to_owned())?;
+    store.set("gone".to_owned(), "removed-value".to_owned())?;
+    let snapshot = store.snapshot();
+    store.set("key".to_owned(), "new-value".to_owned())?;
+    store.remove("gone".to_owned())?;
+
+    store.compact()?;
+    assert_eq!(
+        snapshot.get("key".to_owned())?,
+        Some("old-value".to_owned())
+    );
+    assert_eq!(
+        snapshot.get("gone".to_owned())?,
+        Some("removed-value".to_owned())
+    );
+    assert_eq!(store.get("key".to_owned())?, Some("new-value".to_owned()));
+    assert_eq!(store.get("gone".to_owned())?, None);
+
+    // versions kept for the snapshot never come back after a reopen
+    drop(snapshot);
+    drop(store);
+    let store = KvStoreOptions::new()
+        .compaction(CompactionPolicy::Manual)
+        .open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("new-value".to_owned()));
+    assert_eq!(store.get("gone".to_owned())?, None);
+    for entry in fs::read_dir(temp_dir.path())? {
+        fs::remove_file(entry?.path().with_extension("hint")).ok();
+    }
+    drop(store);
+    let store = KvStoreOptions::new()
+        .compaction(CompactionPolicy::Manual)
+        .open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("new-value".to_owned()));
+    assert_eq!(store.get("gone".to_owned())?, None);
+
+    // once the snapshot is gone, compaction drops the old versions
+    store.compact()?;
+    drop(store);
+    for entry in fs::read_dir(temp_dir.path())? {
+        let content = fs::read(entry?.path())?;
+        assert!(!content.windows(9).any(|w| w == b"old-value"));
+        assert!(!content.windows(13).any(|w| w == b"removed-value"));
+    }
+    Ok(())
+}
+
+#[test]
+fn concurrent_snapshots() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .compaction(CompactionPolicy::StaleBytes(4 * 1024))
+        .open(temp_dir.path())?;
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for i in 0..2000 {
+                let mut batch = WriteBatch::new();
+                batch.put("a".to_owned(), i.to_string());
+                batch.put("b".to_owned(), i.to_string());
+                store.write_batch(batch)?;
+            }
+            Ok(())
+        })
+    };
+    // a snapshot never sees half of a batch, however long it is held
+    let mut held = Vec::new();
+    while !writer.is_finished() {
+        let snapshot = store.snapshot();
+        assert_eq!(snapshot.get("a".to_owned())?, snapshot.get("b".to_owned())?);
+        held.push(snapshot);
+        for snapshot in held.iter().step_by(7) {
+            assert_eq!(snapshot.get("a".to_owned())?, snapshot.get("b".to_owned())?);
+        }
+    }
+    writer.join().unwrap()?;
+    Ok(())
+}
+
+// Binary keys and values must survive replay, compaction and hints.
+#[test]
+fn persist_binary_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let pairs: Vec<(Vec<u8>, Vec<u8>)> = (0..=255u8)
+        .map(|i| (vec![i, 0xff], vec![0xfe, i]))
+        .collect();
+    for (key, val) in &pairs {
+        store.set_bytes(key.clone(), val.clone())?;
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    for (key, val) in &pairs {
+        assert_eq!(store.get_bytes(key.clone())?, Some(val.clone()));
+    }
+    store.compact()?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    for (key, val) in &pairs {
+        assert_eq!(store.get_bytes(key.clone())?, Some(val.clone()));
+    }
+    Ok(())
+}
+
+#[test]
+fn open_engines_by_name() -> Result<()> {
+    for name in ["kvs", "sled"] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let engine = engines::open_engine(name, temp_dir.path())?;
+        engine.set("key".to_owned(), "value".to_owned())?;
+        assert_eq!(engine.get("key".to_owned())?, Some("value".to_owned()));
+        drop(engine);
+
+        let other = if name == "kvs" { "sled" } else { "kvs" };
+        assert!(matches!(
+            engines::open_engine(other, temp_dir.path()),
+            Err(ErrorKind::EngineMismatch { requested, found }) if requested == other && found == name
+        ));
+        let engine = engines::open_engine(name, temp_dir.path())?;
+        assert_eq!(engine.get("key".to_owned())?, Some("value".to_owned()));
+    }
+
+    // the memory engine leaves nothing behind that ties the directory to it
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = engines::open_engine("memory", temp_dir.path())?;
+    engine.set("key".to_owned(), "value".to_owned())?;
+    drop(engine);
+    assert!(fs::read_dir(temp_dir.path())?.next().is_none());
+    engines::open_engine("kvs", temp_dir.path())?;
+    drop(engines::open_engine("memory", temp_dir.path())?);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert!(matches!(
+        engines::open_engine("missing", temp_dir.path()),
+        Err(ErrorKind::UnknownEngine(name)) if name == "missing"
+    ));
+    assert!(fs::read_dir(temp_dir.path())?.next().is_none());
+    Ok(())
+}
+
+fn open_manual_kvs(path: &Path) -> Result<Arc<dyn KvsEngine>> {
+    let store = KvStoreOptions::new()
+        .compaction(CompactionPolicy::Manual)
+        .open(path)?;
+    Ok(Arc::new(store))
+}
+
+#[test]
+fn register_engine() -> Result<()> {
+    assert!(engines::register_engine("manual-kvs", open_manual_kvs).is_none());
+    let names = engines::engine_names();
+    assert!(["kvs", "manual-kvs", "sled"]
+        .iter()
+        .all(|name| names.iter().any(|n| n == name)));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = engines::open_engine("manual-kvs", temp_dir.path())?;
+    engine.set("key".to_owned(), "value".to_owned())?;
+    drop(engine);
+    assert!(matches!(
+        engines::open_engine("kvs", temp_dir.path()),
+        Err(ErrorKind::EngineMismatch { .. })
+    ));
+    Ok(())
+}
+
+#[test]
+fn evict_least_recently_used() -> Result<()> {
+    // room for three 10-byte pairs
+    let engine = MemoryKvsEngine::with_max_bytes(30);
+    for i in 0..3 {
+        engine.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    assert_eq!(engine.size(), 30);
+
+    // reading key0 makes key1 the least recently used
+    assert_eq!(engine.get("key0".to_owned())?, Some("value0".to_owned()));
+    engine.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    for key in ["key0", "key2", "key3"] {
+        assert!(engine.get(key.to_owned())?.is_some());
+    }
+
+    // overwriting a key updates the size instead of adding to it
+    engine.set("key3".to_owned(), "v".to_owned())?;
+    assert_eq!(engine.size(), 25);
+    engine.set("key4".to_owned(), "value4".to_owned())?;
+    assert_eq!(engine.get("key0".to_owned())?, None);
+    assert_eq!(engine.size(), 25);
+
+    // a pair larger than the whole cap is not kept
+    engine.set("big".to_owned(), "x".repeat(64))?;
+    assert_eq!(engine.get("big".to_owned())?, None);
+    assert!(engine.size() <= 30);
+    Ok(())
+}
+
+#[test]
+fn concurrent_memory_engine() -> Result<()> {
+    let engine = MemoryKvsEngine::new();
+    let handles: Vec<_> = (0..8)
+        .map(|t| {
+            let engine = engine.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..100 {
+                    engine.set(format!("key{}-{}", t, i), format!("value{}", i))?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    for t in 0..8 {
+        for i in 0..100 {
+            assert_eq!(
+                engine.get(format!("key{}-{}", t, i))?,
+                Some(format!("value{}", i))
+            );
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn kvs_engine_conformance() -> Result<()> {
+    testing::conformance::<KvStore>()
+}
+
+#[test]
+fn sled_engine_conformance() -> Result<()> {
+    testing::conformance::<SledKvsEngine>()
+}
+
+#[test]
+fn memory_engine_conformance() -> Result<()> {
+    testing::conformance::<MemoryKvsEngine>()
+}
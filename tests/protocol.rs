@@ -0,0 +1,158 @@
+use kvs::protocol::{self, MAX_FRAME_LEN};
+use kvs::{ErrorKind, Message, Response, ResponseErrorKind, Result};
+use std::io::Cursor;
+
+// Keys and values far larger than a single read buffer should round-trip.
+#[test]
+fn large_message_round_trip() -> Result<()> {
+    let key = "k".repeat(10_000);
+    let val = "v".repeat(1_000_000);
+    let mut buf = Vec::new();
+    protocol::send(
+        &mut buf,
+        &Message::Set {
+            key: key.clone(),
+            val: val.clone(),
+        },
+    )?;
+
+    let msg: Message = protocol::receive(&mut Cursor::new(buf))?;
+    match msg {
+        Message::Set { key: k, val: v } => {
+            assert_eq!(k, key);
+            assert_eq!(v, val);
+        }
+        other => panic!("unexpected message {:?}", other),
+    }
+    Ok(())
+}
+
+#[test]
+fn consecutive_frames() -> Result<()> {
+    let mut buf = Vec::new();
+    protocol::write_frame(&mut buf, b"first")?;
+    protocol::write_frame(&mut buf, b"")?;
+    protocol::write_frame(&mut buf, b"third")?;
+
+    let mut reader = Cursor::new(buf);
+    assert_eq!(protocol::read_frame(&mut reader)?, b"first");
+    assert_eq!(protocol::read_frame(&mut reader)?, b"");
+    assert_eq!(protocol::read_frame(&mut reader)?, b"third");
+    Ok(())
+}
+
+#[test]
+fn malformed_payload() -> Result<()> {
+    let mut buf = Vec::new();
+    protocol::write_frame(&mut buf, b"{not json")?;
+    let res: Result<Message> = protocol::receive(&mut Cursor::new(buf));
+    assert!(matches!(res, Err(ErrorKind::Protocol(_))));
+    Ok(())
+}
+
+#[test]
+fn oversized_frame() {
+    let buf = (MAX_FRAME_LEN + 1).to_be_bytes().to_vec();
+    let res = protocol::read_frame(&mut Cursor::new(buf));
+    assert!(matches!(res, Err(ErrorKind::Protocol(_))));
+}
+
+#[test]
+fn truncated_frame() {
+    let mut buf = 10u32.to_be_bytes().to_vec();
+    buf.extend_from_slice(b"short");
+    let res = protocol::read_frame(&mut Cursor::new(buf));
+    assert!(matches!(res, Err(ErrorKind::Io(_))));
+}
+
+// A header announcing the largest frame must fail on the missing payload.
+#[test]
+fn header_without_payload() {
+    let buf = MAX_FRAME_LEN.to_be_bytes().to_vec();
+    let res = protocol::read_frame(&mut Cursor::new(buf));
+    assert!(matches!(res, Err(ErrorKind::Io(_))));
+}
+
+// A stored value equal to "Key not found" must stay distinguishable from a miss.
+#[test]
+fn response_round_trip() -> Result<()> {
+    let responses = vec![
+        Response::Ok,
+        Response::Value(Some("Key not found".to_owned())),
+        Response::Value(None),
+        Response::from(ErrorKind::KeyNotFound),
+    ];
+    let mut buf = Vec::new();
+    for resp in &responses {
+        protocol::send(&mut buf, resp)?;
+    }
+
+    let mut reader = Cursor::new(buf);
+    for resp in responses {
+        assert_eq!(protocol::receive::<_, Response>(&mut reader)?, resp);
+    }
+    Ok(())
+}
+
+#[test]
+fn error_response_kind() {
+    match Response::from(ErrorKind::KeyNotFound) {
+        Response::Error { kind, .. } => assert_eq!(kind, ResponseErrorKind::KeyNotFound),
+        other => panic!("unexpected response {:?}", other),
+    }
+    match Response::from(ErrorKind::Other("boom".to_owned())) {
+        Response::Error { kind, message } => {
+            assert_eq!(kind, ResponseErrorKind::Internal);
+            assert!(message.contains("boom"));
+        }
+        other => panic!("unexpected response {:?}", other),
+    }
+}
+
+#[test]
+fn clean_close_between_frames() -> Result<()> {
+    let mut buf = Vec::new();
+    protocol::send(&mut buf, &Message::Get { key: "key".into() })?;
+
+    let mut reader = Cursor::new(buf);
+    assert!(protocol::try_receive::<_, Message>(&mut reader)?.is_some());
+    assert!(protocol::try_receive::<_, Message>(&mut reader)?.is_none());
+    Ok(())
+}
+
+// Binary values must not blow up on the wire, and must round-trip exactly.
+#[test]
+fn compact_binary_values() -> Result<()> {
+    let val: Vec<u8> = (0..=255).cycle().take(1_000_000).collect();
+    let mut buf = Vec::new();
+    protocol::send(
+        &mut buf,
+        &Message::SetBytes {
+            key: vec![0xff, 0x00],
+            val: val.clone(),
+        },
+    )?;
+    assert!(buf.len() < val.len() * 3 / 2);
+    match protocol::receive(&mut Cursor::new(buf))? {
+        Message::SetBytes { key, val: v } => {
+            assert_eq!(key, [0xff, 0x00]);
+            assert_eq!(v, val);
+        }
+        other => panic!("unexpected message {:?}", other),
+    }
+
+    let mut buf = Vec::new();
+    protocol::send(&mut buf, &Response::Bytes(Some(val.clone())))?;
+    protocol::send(&mut buf, &Response::Bytes(None))?;
+    assert!(buf.len() < val.len() * 3 / 2);
+    let mut reader = Cursor::new(buf);
+    assert_eq!(
+        protocol::receive::<_, Response>(&mut reader)?,
+        Response::Bytes(Some(val))
+    );
+    assert_eq!(
+        protocol::receive::<_, Response>(&mut reader)?,
+        Response::Bytes(None)
+    );
+    Ok(())
+}